@@ -0,0 +1,87 @@
+use crate::types::{Achievement, AchievementRule, QuizSession, UnlockedAchievement, UserScore};
+use chrono::{DateTime, Utc};
+
+impl AchievementRule {
+    /// Whether `score` meets the rule. `completed_quiz` is the quiz the latest
+    /// answer finished, if it finished one.
+    pub fn is_met(&self, score: &UserScore, completed_quiz: Option<&QuizSession>) -> bool {
+        match self {
+            AchievementRule::CorrectAnswers { count } => {
+                score.categories.values().map(|c| c.correct).sum::<u32>() >= *count
+            }
+            AchievementRule::Points { points } => score.score >= *points,
+            AchievementRule::AnswerStreak { count } => score.best_answer_streak >= *count,
+            AchievementRule::DailyStreak { days } => score.best_daily_streak >= *days,
+            AchievementRule::CategoryAnswered { category, count } => {
+                score.categories.get(category).is_some_and(|c| c.answered >= *count)
+            }
+            AchievementRule::PerfectQuiz { min_questions } => completed_quiz.is_some_and(|session| {
+                let asked = session.questions.len() as u32;
+                asked >= *min_questions && session.correct_count(score.user_id) == asked
+            }),
+        }
+    }
+}
+
+/// Records every achievement in `achievements` that the user has just earned
+/// and returns them, so they can be announced.
+pub fn unlock_achievements<'a>(
+    achievements: &'a [Achievement],
+    score: &mut UserScore,
+    completed_quiz: Option<&QuizSession>,
+    now: DateTime<Utc>,
+) -> Vec<&'a Achievement> {
+    let unlocked: Vec<&Achievement> = achievements
+        .iter()
+        .filter(|a| !score.has_achievement(&a.id))
+        .filter(|a| a.rule.is_met(score, completed_quiz))
+        .collect();
+
+    for achievement in &unlocked {
+        score.achievements.push(UnlockedAchievement {
+            id: achievement.id.clone(),
+            unlocked_at: now,
+        });
+    }
+    unlocked
+}
+
+/// Lists the user's badges in the order they were earned, then the ones still
+/// to earn.
+pub fn format_badges(achievements: &[Achievement], score: Option<&UserScore>) -> String {
+    let earned: Vec<String> = score
+        .map(|score| {
+            score
+                .achievements
+                .iter()
+                .filter_map(|unlocked| {
+                    let a = achievements.iter().find(|a| a.id == unlocked.id)?;
+                    Some(format!(
+                        "{} {} - {} ({})",
+                        a.badge,
+                        a.name,
+                        a.description,
+                        unlocked.unlocked_at.format("%d %b %Y")
+                    ))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let locked: Vec<String> = achievements
+        .iter()
+        .filter(|a| score.is_none_or(|score| !score.has_achievement(&a.id)))
+        .map(|a| format!("🔒 {} - {}", a.name, a.description))
+        .collect();
+
+    let mut text = String::from("🏅 Your badges:\n\n");
+    if earned.is_empty() {
+        text.push_str("None yet. Answer some questions with /question to start earning them!");
+    } else {
+        text.push_str(&earned.join("\n"));
+    }
+    if !locked.is_empty() {
+        text.push_str(&format!("\n\nStill to earn:\n\n{}", locked.join("\n")));
+    }
+    text
+}
@@ -0,0 +1,79 @@
+use crate::types::Question;
+
+/// Categories present in `questions` with how many questions each has, in the
+/// order they first appear.
+pub fn category_counts(questions: &[Question]) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for question in questions {
+        match counts.iter_mut().find(|(c, _)| c == &question.category) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((question.category.clone(), 1)),
+        }
+    }
+    counts
+}
+
+/// Resolves what the user typed to one of `categories`, ignoring case, spacing,
+/// punctuation and small typos ("hadeeth", "great masjid", "solah").
+pub fn match_category<'a>(input: &str, categories: &[&'a str]) -> Option<&'a str> {
+    let wanted = normalize(input);
+    if wanted.is_empty() {
+        return None;
+    }
+
+    let normalized: Vec<(&'a str, String)> = categories.iter().map(|c| (*c, normalize(c))).collect();
+
+    if let Some((category, _)) = normalized.iter().find(|(_, n)| *n == wanted) {
+        return Some(category);
+    }
+
+    // "masjid" -> "The Great Masjid", as long as only one category matches
+    let partial: Vec<&'a str> = normalized
+        .iter()
+        .filter(|(_, n)| n.contains(&wanted) || wanted.contains(n.as_str()))
+        .map(|(c, _)| *c)
+        .collect();
+    if partial.len() == 1 {
+        return Some(partial[0]);
+    }
+
+    let max_distance = if wanted.len() >= 5 { 2 } else { 1 };
+    let mut closest: Vec<(usize, &'a str)> = normalized
+        .iter()
+        .map(|(c, n)| (levenshtein(&wanted, n), *c))
+        .filter(|(d, _)| *d <= max_distance)
+        .collect();
+    closest.sort_by_key(|(d, _)| *d);
+    match closest.as_slice() {
+        [(_, category)] => Some(*category),
+        [(best, category), (next, _), ..] if best < next => Some(*category),
+        _ => None,
+    }
+}
+
+fn normalize(text: &str) -> String {
+    let cleaned: String = text
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    let words: Vec<&str> = cleaned.split_whitespace().collect();
+    match words.as_slice() {
+        ["the", rest @ ..] if !rest.is_empty() => rest.join(" "),
+        _ => words.join(" "),
+    }
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -2,15 +2,20 @@ use teloxide::utils::command::BotCommands;
 
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase", description = "Available commands:")]
-
 pub enum Command {
     #[command(description = "Start the bot")]
     Start,
-    #[command(description = "Get a random trivia question")]
-    Question,
-    #[command(description = "Show leaderboard")]
-    Leaderboard,
-    #[command(description = "Start a themed quiz")]
+    #[command(description = "Start a quiz, e.g. /question or /question hadith 10")]
+    Question(String),
+    #[command(description = "Choose your default quiz length, category and difficulty")]
+    Settings,
+    #[command(description = "Show leaderboard, e.g. /leaderboard week, /leaderboard hadith or /leaderboard global month")]
+    Leaderboard(String),
+    #[command(description = "Show your score, streaks and category mastery")]
+    Stats,
+    #[command(description = "Show the badges you have earned")]
+    Badges,
+    #[command(description = "Start a themed quiz, e.g. /theme hadith 10")]
     Theme(String),
     #[command(description = "Opt in to receive reminders")]
     OptIn,
@@ -18,6 +23,8 @@ pub enum Command {
     OptOut,
     #[command(description = "Show your reminder preferences")]
     Preferences,
+    #[command(description = "Set when reminders arrive, e.g. /schedule tz Africa/Lagos or /schedule quiet 22:00-07:00")]
+    Schedule(String),
     #[command(description = "Show help message")]
     Help,
    
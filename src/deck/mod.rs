@@ -0,0 +1,55 @@
+use crate::types::Difficulty;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{HashSet, VecDeque};
+
+/// Deals up to `count` question ids from `pool` without replacement, preferring
+/// ones that aren't in `seen`. Once every question in the pool has been seen the
+/// pool is recycled. Dealt ids are added to `seen`.
+pub fn deal_questions<R: Rng + ?Sized>(
+    pool: &[u32],
+    seen: &mut HashSet<u32>,
+    count: usize,
+    rng: &mut R,
+) -> Vec<u32> {
+    let count = count.min(pool.len());
+
+    let mut unseen: Vec<u32> = pool.iter().copied().filter(|id| !seen.contains(id)).collect();
+    unseen.shuffle(rng);
+    let mut dealt: Vec<u32> = unseen.into_iter().take(count).collect();
+
+    if dealt.len() < count {
+        // Everything in this pool has been seen, start the cycle again
+        for id in pool {
+            seen.remove(id);
+        }
+        let mut recycled: Vec<u32> = pool.iter().copied().filter(|id| !dealt.contains(id)).collect();
+        recycled.shuffle(rng);
+        let missing = count - dealt.len();
+        dealt.extend(recycled.into_iter().take(missing));
+    }
+
+    seen.extend(dealt.iter().copied());
+    dealt
+}
+
+// Answers needed at a level before adaptive mode moves up or down
+pub const ADAPTIVE_MIN_ANSWERS: usize = 5;
+
+/// Moves one level harder when at least 80% of the recent answers were correct
+/// and one level easier when fewer than half were.
+pub fn adjust_difficulty(level: Difficulty, recent_answers: &VecDeque<bool>) -> Difficulty {
+    if recent_answers.len() < ADAPTIVE_MIN_ANSWERS {
+        return level;
+    }
+
+    let correct = recent_answers.iter().filter(|c| **c).count();
+    let accuracy = correct as f64 / recent_answers.len() as f64;
+    if accuracy >= 0.8 {
+        level.harder()
+    } else if accuracy < 0.5 {
+        level.easier()
+    } else {
+        level
+    }
+}
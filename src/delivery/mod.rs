@@ -0,0 +1,192 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use teloxide::types::ChatId;
+use teloxide::{ApiError, RequestError};
+use tokio::time::Instant;
+
+// Telegram allows about 30 messages a second across all chats; stay under it
+pub const BROADCAST_RATE_PER_SEC: f64 = 25.0;
+pub const BROADCAST_BURST: f64 = 25.0;
+
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 4;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Spaces out sends: holds up to `capacity` tokens, refilled at `rate` a
+/// second, and each send takes one.
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, rate: f64) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+            paused_until: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// Waits until a token is free and takes it.
+    pub async fn acquire(&mut self) {
+        if let Some(until) = self.paused_until.take() {
+            tokio::time::sleep_until(until).await;
+            // Start slowly again rather than with a burst
+            self.tokens = 0.0;
+            self.refilled_at = until;
+        }
+        self.refill();
+        if self.tokens < 1.0 {
+            let wait = (1.0 - self.tokens) / self.rate;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+            self.refill();
+        }
+        self.tokens = (self.tokens - 1.0).max(0.0);
+    }
+
+    // Telegram asked us to back off, so nothing goes out until it has passed
+    fn pause(&mut self, wait: Duration) {
+        self.paused_until = Some(Instant::now() + wait);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryOutcome {
+    Sent,
+    Failed,
+    // The user blocked the bot or can't be messaged any more
+    Blocked,
+}
+
+/// What happened to each message of one broadcast.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeliveryReport {
+    pub sent: usize,
+    pub failed: Vec<ChatId>,
+    pub blocked: Vec<ChatId>,
+    // Sends that went through after at least one retry
+    pub retried: usize,
+}
+
+impl DeliveryReport {
+    fn record(&mut self, chat_id: ChatId, outcome: DeliveryOutcome) {
+        match outcome {
+            DeliveryOutcome::Sent => self.sent += 1,
+            DeliveryOutcome::Failed => self.failed.push(chat_id),
+            DeliveryOutcome::Blocked => self.blocked.push(chat_id),
+        }
+    }
+}
+
+impl fmt::Display for DeliveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} sent ({} after retrying), {} failed, {} blocked",
+            self.sent,
+            self.retried,
+            self.failed.len(),
+            self.blocked.len()
+        )
+    }
+}
+
+enum SendError {
+    RetryAfter(Duration),
+    Transient,
+    Blocked,
+    Permanent,
+}
+
+fn classify(error: &RequestError) -> SendError {
+    match error {
+        RequestError::RetryAfter(wait) => SendError::RetryAfter(*wait),
+        RequestError::Network(_) | RequestError::Io(_) => SendError::Transient,
+        RequestError::Api(
+            ApiError::BotBlocked
+            | ApiError::UserDeactivated
+            | ApiError::ChatNotFound
+            | ApiError::BotKicked
+            | ApiError::BotKickedFromSupergroup
+            | ApiError::CantInitiateConversation,
+        ) => SendError::Blocked,
+        _ => SendError::Permanent,
+    }
+}
+
+/// Sends broadcasts through one rate limiter, retrying network errors with
+/// backoff and waiting out Telegram's flood control.
+pub struct DeliveryQueue {
+    bucket: TokenBucket,
+    max_attempts: u32,
+}
+
+impl Default for DeliveryQueue {
+    fn default() -> Self {
+        Self::new(TokenBucket::new(BROADCAST_BURST, BROADCAST_RATE_PER_SEC), MAX_DELIVERY_ATTEMPTS)
+    }
+}
+
+impl DeliveryQueue {
+    pub fn new(bucket: TokenBucket, max_attempts: u32) -> Self {
+        Self { bucket, max_attempts }
+    }
+
+    /// Sends every message with `send`, one at a time, and reports how it went.
+    pub async fn broadcast<F, Fut>(&mut self, messages: Vec<(ChatId, String)>, send: F) -> DeliveryReport
+    where
+        F: Fn(ChatId, String) -> Fut,
+        Fut: Future<Output = Result<(), RequestError>>,
+    {
+        let mut report = DeliveryReport::default();
+        for (chat_id, text) in messages {
+            let mut attempt = 1;
+            let outcome = loop {
+                self.bucket.acquire().await;
+                let error = match send(chat_id, text.clone()).await {
+                    Ok(()) => break DeliveryOutcome::Sent,
+                    Err(error) => error,
+                };
+                match classify(&error) {
+                    SendError::Blocked => break DeliveryOutcome::Blocked,
+                    SendError::Permanent => {
+                        log::error!("Failed to send to {}: {}", chat_id, error);
+                        break DeliveryOutcome::Failed;
+                    }
+                    _ if attempt >= self.max_attempts => {
+                        log::error!("Giving up on message to {} after {} attempts: {}", chat_id, attempt, error);
+                        break DeliveryOutcome::Failed;
+                    }
+                    SendError::RetryAfter(wait) => {
+                        log::warn!("Flood control hit sending to {}, waiting {:?}", chat_id, wait);
+                        self.bucket.pause(wait);
+                    }
+                    SendError::Transient => {
+                        let backoff = RETRY_BACKOFF * 2u32.pow(attempt - 1);
+                        log::warn!("Failed to send to {}: {}. Retrying in {:?}", chat_id, error, backoff);
+                        tokio::time::sleep(backoff).await;
+                    }
+                }
+                attempt += 1;
+            };
+            if outcome == DeliveryOutcome::Sent && attempt > 1 {
+                report.retried += 1;
+            }
+            report.record(chat_id, outcome);
+        }
+        report
+    }
+}
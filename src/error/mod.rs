@@ -4,6 +4,7 @@ use std::fmt;
 pub enum ScoreError {
     IoError(std::io::Error),
     SerdeError(serde_json::Error),
+    Database(rusqlite::Error),
 }
 
 impl std::error::Error for ScoreError {}
@@ -13,6 +14,7 @@ impl fmt::Display for ScoreError {
         match self {
             ScoreError::IoError(e) => write!(f, "IO error: {}", e),
             ScoreError::SerdeError(e) => write!(f, "Serialization error: {}", e),
+            ScoreError::Database(e) => write!(f, "Database error: {}", e),
         }
     }
 }
@@ -27,4 +29,26 @@ impl From<serde_json::Error> for ScoreError {
     fn from(err: serde_json::Error) -> Self {
         ScoreError::SerdeError(err)
     }
-}
\ No newline at end of file
+}
+
+impl From<rusqlite::Error> for ScoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        ScoreError::Database(err)
+    }
+}
+#[derive(Debug, PartialEq)]
+pub enum CallbackDataError {
+    Malformed(String),
+    UnsupportedVersion(u8),
+}
+
+impl std::error::Error for CallbackDataError {}
+
+impl fmt::Display for CallbackDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallbackDataError::Malformed(data) => write!(f, "Malformed callback data: {:?}", data),
+            CallbackDataError::UnsupportedVersion(v) => write!(f, "Unsupported callback data version: {}", v),
+        }
+    }
+}
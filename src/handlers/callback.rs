@@ -1,18 +1,21 @@
 use crate::BotState;
 use crate::types::GameState;
 use chrono::Utc;
-use rand::seq::IteratorRandom;
+use std::collections::HashMap;
 use std::error::Error;
 use std::sync::Arc;
 use teloxide::dispatching::DpHandlerDescription;
 use teloxide::prelude::*;
 use teloxide::types::CallbackQuery;
-use crate::keyboard::create_keyboard;
-use crate::types::{ActiveQuestion, UserScore};
+use crate::achievements::unlock_achievements;
+use crate::category::category_counts;
+use crate::keyboard::{create_keyboard, CallbackData, QuizAction};
+use crate::handlers::{handle_settings_callback, handle_subscription_callback, schedule_question_timeout, start_new_quiz};
+use crate::types::{apply_streak_multiplier, speed_bonus, streak_multiplier, ActiveQuestion, CompletedQuiz, QuizParticipant, QuizSession, ScoreEvent, SessionAnswer, UserScore};
 
 pub fn recursive_callback_handler(
     state: Arc<BotState>,
-) -> dptree::Handler<'static, DependencyMap, Result<(), Box<dyn Error + Send + Sync>>, DpHandlerDescription> 
+) -> dptree::Handler<'static, DependencyMap, Result<(), Box<dyn Error + Send + Sync>>, DpHandlerDescription>
 {
     Update::filter_callback_query()
         .endpoint(move |bot: Bot, q: CallbackQuery| {
@@ -31,114 +34,456 @@ pub async fn handle_callback_query(
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     if let (Some(message), Some(data)) = (query.message, query.data) {
         let chat_id = message.chat.id;
-        
-        if data == "end_quiz" {
-            let mut active_questions = state.active_questions.lock().await;
-            if active_questions.remove(&chat_id.0).is_some() {
-                let scores = state.user_scores.lock().await;
-                if let Some(user_score) = scores.get(&chat_id.0) {
-                    bot.send_message(
-                        chat_id,
-                        format!("Quiz ended! Your final score: {} points", user_score.score),
-                    )
+        let is_private = message.chat.is_private();
+        let user_id = query.from.id.0 as i64;
+        let username = query.from.first_name.clone();
+
+        let payload = match CallbackData::decode(&data) {
+            Ok(CallbackData::Quiz(payload)) => payload,
+            Ok(CallbackData::Settings(settings)) => {
+                return handle_settings_callback(bot, query.id, &message, user_id, settings, state).await;
+            }
+            Ok(CallbackData::Subscription(subscription)) => {
+                return handle_subscription_callback(bot, query.id, &message, &username, subscription, state).await;
+            }
+            Ok(CallbackData::Theme(theme)) => {
+                bot.answer_callback_query(query.id).await?;
+                let category = category_counts(&state.questions)
+                    .into_iter()
+                    .nth(theme.category_index)
+                    .map(|(category, _)| category);
+                match category {
+                    Some(category) => {
+                        let mut settings = state.quiz_settings(user_id).await;
+                        settings.category = Some(category);
+                        start_new_quiz(bot, chat_id, user_id, &settings, state).await?;
+                    }
+                    None => {
+                        bot.send_message(chat_id, "No questions found for this category!").await?;
+                    }
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                log::warn!("Rejected callback from {}: {}", user_id, e);
+                bot.answer_callback_query(query.id)
+                    .text("This button has expired. Use /question to start a new quiz.")
                     .await?;
+                return Ok(());
+            }
+        };
+
+        // Record the answer while holding the lock so that two members answering
+        // at the same time can't both close the question
+        let (question, option_index, is_correct, points, bonus, streak, adaptive, closed_question) = {
+            let mut active_questions = state.active_questions.lock().await;
+            let active_question = match active_questions.get_mut(&chat_id.0) {
+                Some(active_question)
+                    if active_question.quiz_id == payload.quiz_id
+                        && active_question.question_id == payload.question_id =>
+                {
+                    active_question
+                }
+                // An earlier question of the quiz that is still running
+                Some(active_question) if active_question.quiz_id == payload.quiz_id => {
+                    drop(active_questions);
+                    bot.answer_callback_query(query.id)
+                        .text("This question is no longer active.")
+                        .await?;
+                    return Ok(());
                 }
+                // Finished, replaced, or lost in a restart
+                _ => {
+                    drop(active_questions);
+                    bot.answer_callback_query(query.id)
+                        .text("⌛ This quiz has expired. Use /question to start a new one!")
+                        .show_alert(true)
+                        .await?;
+                    return Ok(());
+                }
+            };
+            // Whatever happens below changes the quiz
+            state.mark_quizzes_dirty();
+
+            if payload.action == QuizAction::End {
+                let ended = active_questions.remove(&chat_id.0);
+                drop(active_questions);
+                if let Some(mut active_question) = ended {
+                    // A question nobody got round to answering doesn't count against anyone
+                    if active_question.answers.is_empty() {
+                        active_question.session.questions.pop();
+                    }
+                    send_quiz_summary(&bot, chat_id, "Quiz ended!", &active_question, is_private, &state).await?;
+                }
+                bot.answer_callback_query(query.id).await?;
                 return Ok(());
             }
-        }
 
-        let current_question = {
-            let active_questions = state.active_questions.lock().await;
-            active_questions.get(&chat_id.0).cloned()
+            let question = match state.question(payload.question_id) {
+                Some(question) => question.clone(),
+                None => {
+                    active_questions.remove(&chat_id.0);
+                    drop(active_questions);
+                    bot.answer_callback_query(query.id)
+                        .text("This question is no longer available.")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let option_index = payload.option_index as usize;
+
+            if active_question.answers.contains_key(&user_id) {
+                drop(active_questions);
+                bot.answer_callback_query(query.id)
+                    .text("You've already answered this question.")
+                    .await?;
+                return Ok(());
+            }
+
+            let is_correct = question.is_correct_option(option_index);
+            let bonus = match active_question.time_limit {
+                Some(time_limit) if is_correct => {
+                    speed_bonus(question.points, active_question.asked_at.elapsed(), time_limit)
+                }
+                _ => 0,
+            };
+            active_question.answers.insert(user_id, option_index);
+            let participant = active_question
+                .session
+                .participants
+                .entry(user_id)
+                .or_insert_with(|| QuizParticipant::new(username.clone()));
+            let streak = if is_correct { participant.streak + 1 } else { 0 };
+            let points = if is_correct { apply_streak_multiplier(question.points, streak) } else { 0 };
+            participant.record_answer(is_correct, points + bonus);
+            active_question.session.answers.push(SessionAnswer {
+                user_id,
+                question_id: question.id,
+                option_index,
+                is_correct,
+                points: points + bonus,
+            });
+
+            let adaptive = active_question.adaptive;
+
+            // In a group the question stays open until somebody gets it right, or
+            // everyone who played the last question has got it wrong so it can't stall
+            let closed_question = if is_correct || is_private || active_question.all_expected_players_answered() {
+                active_questions.remove(&chat_id.0)
+            } else {
+                None
+            };
+            (question, option_index, is_correct, points, bonus, streak, adaptive, closed_question)
         };
 
-        if let Some(active_question) = current_question {
-            let question = &active_question.question;
-            let is_correct = data == question.correct_answer;
-            let game_state = active_question.game_state;
+        // Answered first so the button stops spinning even if a send below fails
+        if let Err(e) = bot.answer_callback_query(query.id).await {
+            log::warn!("Failed to answer callback query from {}: {}", user_id, e);
+        }
+
+        // From here on a failed send is only logged, so the quiz still moves on
+        if let Some(closed_question) = &closed_question {
+            let selected_answer = question.get_options().get(option_index).cloned();
+            if let Err(e) = bot
+                .edit_message_reply_markup(chat_id, closed_question.message_id)
+                .reply_markup(create_keyboard(&question, closed_question.quiz_id, selected_answer.as_deref(), true, true))
+                .await
+            {
+                log::warn!("Failed to update the keyboard in chat {}: {}", chat_id, e);
+            }
+        }
 
-            bot.edit_message_reply_markup(chat_id, message.id)
-                .reply_markup(create_keyboard(question, Some(&data), true, true))
-                .await?;
+        // Only adaptive quizzes are dealt at the player's level, so only they move it
+        if adaptive {
+            state.record_answer(user_id, is_correct).await;
+        }
 
+        let unlocked = {
+            // Every answer counts towards the daily play streak, not just correct ones
+            let mut scores = state.user_scores.lock().await;
+            let score = scores
+                .entry(user_id)
+                .or_insert_with(|| UserScore::new(user_id, username.clone()));
+
+            score.record_play(Utc::now().date_naive());
+            score.best_answer_streak = score.best_answer_streak.max(streak);
+            score.record_category_answer(&question.category, question.id, is_correct, points + bonus);
             if is_correct {
-                let mut scores = state.user_scores.lock().await;
-                let score = scores.entry(chat_id.0).or_insert(UserScore {
-                    user_id: chat_id.0,
-                    username: query.from.first_name.clone(),
-                    score: 0,
-                    last_answer_time: Utc::now(),
-                });
-                
-                score.score += question.points;
+                score.score += points + bonus;
                 score.last_answer_time = Utc::now();
-                
-                // Save scores after updating
-                drop(scores); // Release the lock before saving
-                if let Err(e) = state.save_scores().await {
-                    log::error!("Failed to save scores: {}", e);
-                }
-                
-                bot.send_message(
-                    chat_id,
-                    format!("🎉 Correct! You earned {} points!", question.points),
-                )
-                .await?;
+            }
+
+            let completed_quiz = closed_question
+                .as_ref()
+                .filter(|q| q.is_last_question())
+                .map(|q| &q.session);
+            unlock_achievements(&state.achievements, score, completed_quiz, Utc::now())
+        };
+
+        state
+            .record_score_event(ScoreEvent {
+                user_id,
+                username: username.clone(),
+                chat_id: Some(chat_id.0),
+                question_id: question.id,
+                category: Some(question.category.clone()),
+                is_correct,
+                points: points + bonus,
+                at: Utc::now(),
+            })
+            .await;
+
+        // Saved by the background writer
+        state.mark_scores_dirty();
+
+        let mut replies = Vec::new();
+        if is_correct {
+            let mut extras = String::new();
+            if streak_multiplier(streak) > 100 {
+                extras.push_str(&format!(
+                    " 🔥 {} in a row (x{})",
+                    streak,
+                    streak_multiplier(streak) as f64 / 100.0
+                ));
+            }
+            if bonus > 0 {
+                extras.push_str(&format!(" (+{} speed bonus ⚡)", bonus));
+            }
+            replies.push(if is_private {
+                format!("🎉 Correct! You earned {} points!{}", points, extras)
             } else {
-                bot.send_message(chat_id, "❌ Sorry, that's incorrect!")
-                    .await?;
+                format!("🎉 Correct, {}! You earned {} points!{}", username, points, extras)
+            });
+        } else if is_private {
+            replies.push("❌ Sorry, that's incorrect!".to_string());
+        } else {
+            replies.push(format!("❌ Sorry {}, that's incorrect!", username));
+            if closed_question.is_some() {
+                replies.push(format!("Nobody got that one. The answer was: {}", question.correct_answer));
             }
-                        // Check if we should continue with next question
-            if let GameState::InProgress { questions_asked, max_questions } = game_state {
-                if questions_asked < max_questions {
-                    // Generate next question
-                    let next_question = {
-                        let mut rng = state.rng.lock().await;
-                        state.questions.iter().choose(&mut *rng).unwrap().clone()
-                    };
-
-                    // Send next question
-                    let sent_message = bot
-                        .send_message(
-                            chat_id,
-                            &format!("Question {}/{}\n\n{}", 
-                                    questions_asked + 1, 
-                                    max_questions, 
-                                    next_question.question)
-                        )
-                        .reply_markup(create_keyboard(&next_question, None, false, true))
-                        .await?;
+        }
 
-                    // Update active question with new state
-                    let mut active_questions = state.active_questions.lock().await;
-                    active_questions.insert(
-                        chat_id.0,
-                        ActiveQuestion {
-                            question: next_question,
-                            message_id: sent_message.id,
-                            game_state: GameState::InProgress {
-                                questions_asked: questions_asked + 1,
-                                max_questions,
-                            },
-                        },
-                    );
-                } else {
-                    // End of quiz
-                    let scores = state.user_scores.lock().await;
-                    if let Some(user_score) = scores.get(&chat_id.0) {
-                        bot.send_message(
-                            chat_id,
-                            format!("Quiz completed! Your final score: {} points", user_score.score),
-                        )
-                        .await?;
-                    }
-                    let mut active_questions = state.active_questions.lock().await;
-                    active_questions.remove(&chat_id.0);
-                }
+        for achievement in unlocked {
+            replies.push(if is_private {
+                format!("🏅 Achievement unlocked: {} {}\n{}", achievement.badge, achievement.name, achievement.description)
+            } else {
+                format!(
+                    "🏅 {} unlocked {} {}\n{}",
+                    username, achievement.badge, achievement.name, achievement.description
+                )
+            });
+        }
+
+        for reply in replies {
+            if let Err(e) = bot.send_message(chat_id, reply).await {
+                log::warn!("Failed to send answer feedback to chat {}: {}", chat_id, e);
             }
         }
 
-        bot.answer_callback_query(query.id).await?;
+        // Check if we should continue with next question
+        if let Some(closed_question) = closed_question {
+            advance_quiz(&bot, chat_id, closed_question, &state).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the next question of a quiz whose current question has just been
+/// closed (answered or timed out), or the final summary if it was the last one.
+pub async fn advance_quiz(
+    bot: &Bot,
+    chat_id: ChatId,
+    mut closed_question: ActiveQuestion,
+    state: &Arc<BotState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let GameState::InProgress { questions_asked, max_questions } = closed_question.game_state;
+    // Take the next question from the deck dealt when the quiz started
+    let next_question = if !closed_question.is_last_question() {
+        let next_id = closed_question.remaining_questions.remove(0);
+        state.question(next_id).cloned()
+    } else {
+        None
+    };
+
+    let next_question = match next_question {
+        Some(next_question) => next_question,
+        None => {
+            // End of quiz
+            if let Err(e) = send_quiz_summary(bot, chat_id, "Quiz completed!", &closed_question, chat_id.is_user(), state).await {
+                log::error!("Failed to send the quiz summary in chat {}: {}", chat_id, e);
+            }
+            return Ok(());
+        }
+    };
+
+    // The quiz is no longer active, so a failed send ends it with a summary
+    // rather than leaving its buttons to expire
+    let sent_message = match bot
+        .send_message(
+            chat_id,
+            format!("Question {}/{}\n\n{}",
+                    questions_asked + 1,
+                    max_questions,
+                    next_question.question)
+        )
+        .reply_markup(create_keyboard(&next_question, closed_question.quiz_id, None, false, true))
+        .await
+    {
+        Ok(sent_message) => sent_message,
+        Err(e) => {
+            log::error!("Failed to send the next question in chat {}: {}", chat_id, e);
+            if let Err(e) = send_quiz_summary(bot, chat_id, "Quiz ended early!", &closed_question, chat_id.is_user(), state).await {
+                log::error!("Failed to send the quiz summary in chat {}: {}", chat_id, e);
+            }
+            return Ok(());
+        }
+    };
+
+    // Carry the session over so the final summary covers the whole quiz
+    let mut next_active_question = ActiveQuestion::new(
+        closed_question.quiz_id,
+        next_question.id,
+        sent_message.id,
+        GameState::InProgress {
+            questions_asked: questions_asked + 1,
+            max_questions,
+        },
+    );
+    next_active_question.remaining_questions = closed_question.remaining_questions;
+    next_active_question.session = closed_question.session;
+    next_active_question.session.questions.push(next_question.id);
+    next_active_question.time_limit = closed_question.time_limit;
+    next_active_question.adaptive = closed_question.adaptive;
+    next_active_question.expected_players = closed_question.answers.keys().copied().collect();
+
+    schedule_question_timeout(bot.clone(), chat_id, &next_active_question, state.clone());
+    state.active_questions.lock().await.insert(chat_id.0, next_active_question);
+    state.mark_quizzes_dirty();
+    Ok(())
+}
+
+async fn send_quiz_summary(
+    bot: &Bot,
+    chat_id: ChatId,
+    heading: &str,
+    active_question: &ActiveQuestion,
+    is_private: bool,
+    state: &Arc<BotState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let session = &active_question.session;
+    if !session.answers.is_empty() {
+        state
+            .record_completed_quiz(CompletedQuiz {
+                chat_id: chat_id.0,
+                finished_at: Utc::now(),
+                session: session.clone(),
+            })
+            .await;
     }
+
+    let text = if is_private {
+        // In a private chat the chat id is the user id
+        let user_id = chat_id.0;
+        let mut text = format!(
+            "{}\n\n✅ {}/{} correct\n⭐ Points gained: {}\n🎯 Accuracy: {}%\n⏱ Time taken: {}",
+            heading,
+            session.correct_count(user_id),
+            session.questions.len(),
+            session.points_earned(user_id),
+            session.accuracy(user_id),
+            format_duration(Utc::now() - session.started_at)
+        );
+
+        let scores = state.user_scores.lock().await;
+        if let Some(user_score) = scores.get(&user_id) {
+            let current_streak = session.participants.get(&user_id).map_or(0, |p| p.streak);
+            text.push_str(&format!(
+                "\n\n🔥 Answer streak: {} (best {})\n📅 Daily streak: {} days (best {})\n🏅 Total score: {} points",
+                current_streak,
+                user_score.best_answer_streak,
+                user_score.current_daily_streak(Utc::now().date_naive()),
+                user_score.best_daily_streak,
+                user_score.score
+            ));
+        }
+        drop(scores);
+
+        text.push_str(&format_missed_review(session, Some(user_id), state));
+        text
+    } else {
+        format!(
+            "{}\n\n{}{}",
+            heading,
+            format_group_summary(&session.participants),
+            format_missed_review(session, None, state)
+        )
+    };
+
+    bot.send_message(chat_id, text).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+fn format_duration(elapsed: chrono::Duration) -> String {
+    let secs = elapsed.num_seconds().max(0);
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m {}s", secs / 60, secs % 60)
+    }
+}
+
+// Lists the missed questions with their correct answers, or nothing if none were
+// missed. For a single user their own wrong pick is shown alongside.
+fn format_missed_review(session: &QuizSession, user_id: Option<i64>, state: &BotState) -> String {
+    let review = session
+        .missed_questions(user_id)
+        .into_iter()
+        .filter_map(|id| state.question(id))
+        .enumerate()
+        .map(|(i, q)| {
+            let chosen = user_id
+                .and_then(|user_id| session.chosen_option(user_id, q.id))
+                .and_then(|index| q.get_options().get(index).cloned());
+            match chosen {
+                Some(chosen) => format!("{}. {}\n   ❌ {}\n   ✅ {}", i + 1, q.question, chosen, q.correct_answer),
+                None => format!("{}. {}\n   ✅ {}", i + 1, q.question, q.correct_answer),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if review.is_empty() {
+        String::new()
+    } else {
+        format!("\n\n📖 Review what you missed:\n\n{}", review.join("\n\n"))
+    }
+}
+
+/// Formats each participant's result for the end-of-quiz message in a group,
+/// best score first.
+pub fn format_group_summary(participants: &HashMap<i64, QuizParticipant>) -> String {
+    if participants.is_empty() {
+        return "Nobody answered any questions.".to_string();
+    }
+
+    let mut results: Vec<_> = participants.values().collect();
+    results.sort_by(|a, b| b.points.cmp(&a.points).then(b.correct.cmp(&a.correct)));
+
+    let lines = results
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            format!(
+                "{}. {} - {}/{} correct, {} points, best streak {}",
+                i + 1,
+                p.username,
+                p.correct,
+                p.answered,
+                p.points,
+                p.best_streak
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!("📋 Results:\n\n{}", lines)
+}
@@ -1,10 +1,13 @@
 use crate::{BotState, Command};
 use std::error::Error;
 use std::sync::Arc;
+use chrono::Utc;
 use teloxide::prelude::*;
-use rand::seq::IteratorRandom;
-use crate::types::{ActiveQuestion, GameState};
-use crate::keyboard::create_keyboard;
+use crate::types::{ActiveQuestion, Difficulty, GameState, QuizSettings, UserScore};
+use crate::keyboard::{create_category_keyboard, create_keyboard};
+use crate::category::{category_counts, match_category};
+use crate::achievements::format_badges;
+use crate::leaderboard::{format_leaderboard, parse_leaderboard_args, rank_category_scores, rank_events, rank_scores};
 use teloxide::utils::command::BotCommands;
 
 use crate::handlers::*;
@@ -20,66 +23,143 @@ pub async fn command_handler(
             bot.send_message(
                 msg.chat.id,
                 "
-                \n 🕌 Use /question for a random question to deepen your Islamic knowledge.
-                \n 📚 Use /theme <category> for themed quizzes on various topics.
-                \n 🏆 Use /leaderboard to see top scores and track your progress.
-                \n 🔔 Use /optin to receive daily Islamic and Sunnah reminders (4 times a day) designed to help you build habits through repetition. Sunnah reminders change weekly to keep things fresh and engaging.
+                \n 🕌 Use /question for a quiz to deepen your Islamic knowledge, e.g. /question hadith 10.
+                \n ⚙️ Use /settings to choose your default quiz length, category and difficulty.
+                \n 📚 Use /theme to pick a category, or /theme <category> [questions] for a themed quiz.
+                \n 🏆 Use /leaderboard to see top scores (add today, week, month or global to change the table) and /stats to track your progress and streaks.
+                \n 🏅 Use /badges to see the achievements you've unlocked and the ones still to earn.
+                \n 🔔 Use /optin to receive daily Islamic and Sunnah reminders (4 times a day unless you change it with /schedule) designed to help you build habits through repetition. Sunnah reminders change weekly to keep things fresh and engaging.
+                \n 🕰 Use /schedule to set your timezone, delivery times and quiet hours, and /preferences to choose duas, sunnah acts or both.
                 \n ❓ Use /help for additional guidance.
                 "
             )
             .await?;
         }
-        Command::Question => {
-            start_new_quiz(bot, msg.chat.id, 5, state).await? // Start a 5-question quiz
+        Command::Question(args) => {
+            let user_id = msg.from().map_or(msg.chat.id.0, |u| u.id.0 as i64);
+            let defaults = state.quiz_settings(user_id).await;
+
+            if args.trim().is_empty() {
+                return start_new_quiz(bot, msg.chat.id, user_id, &defaults, state).await;
+            }
+
+            let categories = category_counts(&state.questions);
+            match parse_quiz_args(&args, defaults, &categories) {
+                Ok(settings) => {
+                    // Whatever was asked for becomes the user's new default
+                    state.set_quiz_settings(user_id, settings.clone()).await;
+                    start_new_quiz(bot, msg.chat.id, user_id, &settings, state).await?
+                }
+                Err(e) => {
+                    bot.send_message(msg.chat.id, e).await?;
+                }
+            }
         }
-        Command::Theme(category) => {
-            let themed_questions: Vec<_> = state
-                .questions
-                .iter()
-                .filter(|q| q.category.to_lowercase() == category.to_lowercase())
-                .collect();
-
-            let question = {
-                let mut rng = state.rng.lock().await;
-                themed_questions.iter().choose(&mut *rng)
-            };
+        Command::Settings => {
+            handle_settings(bot, msg, state).await?;
+        }
+        Command::Theme(args) => {
+            let user_id = msg.from().map_or(msg.chat.id.0, |u| u.id.0 as i64);
+            let (category, length) = parse_theme_args(&args);
+            let categories = category_counts(&state.questions);
 
-            if let Some(question) = question.cloned() {
-                let question = question.clone();
-                let sent_message = bot
-                    .send_message(msg.chat.id, &question.question)
-                    .reply_markup(create_keyboard(&question, None, false, true))
+            if category.is_empty() {
+                bot.send_message(msg.chat.id, "📚 Pick a category for your themed quiz:")
+                    .reply_markup(create_category_keyboard(&categories))
                     .await?;
+                return Ok(());
+            }
 
-                state.active_questions.lock().await.insert(
-                    msg.chat.id.0,
-                    ActiveQuestion {
-                        question: question.clone(),
-                        message_id: sent_message.id,
-                        game_state: GameState::Ended,
-                    },
-                );
-            } else {
-                bot.send_message(msg.chat.id, "No questions found for this category!")
+            let names: Vec<&str> = categories.iter().map(|(c, _)| c.as_str()).collect();
+            match match_category(&category, &names) {
+                Some(matched) => {
+                    // Same length as the theme buttons unless one was asked for
+                    let mut settings = state.quiz_settings(user_id).await;
+                    settings.category = Some(matched.to_string());
+                    if let Some(length) = length {
+                        settings = settings.with_length(length);
+                    }
+                    start_new_quiz(bot, msg.chat.id, user_id, &settings, state).await?
+                }
+                None => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("No category matches \"{}\". Pick one of these instead:", category),
+                    )
+                    .reply_markup(create_category_keyboard(&categories))
                     .await?;
+                }
             }
         }
-        Command::Leaderboard => {
-            let scores = state.user_scores.lock().await;
-            let mut scores: Vec<_> = scores.values().collect();
-            scores.sort_by(|a, b| b.score.cmp(&a.score));
-            
-            let leaderboard = scores
-                .iter()
-                .take(10)
-                .enumerate()
-                .map(|(i, user)| format!("{}. {} - {} points", i + 1, user.username, user.score))
-                .collect::<Vec<_>>()
-                .join("\n");
-                
-            bot.send_message(msg.chat.id, format!("🏆 Leaderboard:\n\n{}", leaderboard))
+        Command::Leaderboard(args) => {
+            let user_id = msg.from().map_or(msg.chat.id.0, |u| u.id.0 as i64);
+            let categories = category_counts(&state.questions);
+            let names: Vec<&str> = categories.iter().map(|(c, _)| c.as_str()).collect();
+            let query = match parse_leaderboard_args(&args, &names) {
+                Some(query) => query,
+                None => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "Usage: /leaderboard [global] [category] [today|week|month|all]\nCategories: {}",
+                            names.join(", ")
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+
+            // Groups see their own standings unless they ask for the global table
+            let group = (!query.global && !msg.chat.is_private()).then_some(msg.chat.id.0);
+            let since = query.window.start(Utc::now(), state.leaderboard_timezone);
+            let entries = match (since, group, &query.category) {
+                (None, None, None) => rank_scores(&*state.user_scores.lock().await),
+                (_, _, None) => rank_events(&*state.score_events.lock().await, since, group),
+                (None, None, Some(category)) => rank_category_scores(&*state.user_scores.lock().await, category),
+                (_, _, Some(category)) => {
+                    let events = state.score_events.lock().await;
+                    let in_category = events.iter().filter(|e| e.category.as_ref() == Some(category));
+                    rank_events(in_category, since, group)
+                }
+            };
+
+            let mut title = match group {
+                Some(_) => msg.chat.title().unwrap_or("This group").to_string(),
+                None => "Global".to_string(),
+            };
+            if let Some(category) = &query.category {
+                title.push_str(&format!(", {}", category));
+            }
+            title.push_str(&format!(", {}", query.window.title()));
+
+            bot.send_message(msg.chat.id, format_leaderboard(&title, &entries, user_id))
                 .await?;
         }
+        Command::Stats => {
+            let user_id = msg.from().map_or(msg.chat.id.0, |u| u.id.0 as i64);
+            let scores = state.user_scores.lock().await;
+            let text = match scores.get(&user_id) {
+                Some(user_score) => format!(
+                    "📊 Stats for {}:\n\n🏅 Score: {} points\n🔥 Best answer streak: {}\n📅 Daily streak: {} days (best {})\n\n📚 Mastery:\n{}",
+                    user_score.username,
+                    user_score.score,
+                    user_score.best_answer_streak,
+                    user_score.current_daily_streak(Utc::now().date_naive()),
+                    user_score.best_daily_streak,
+                    format_category_mastery(user_score, &category_counts(&state.questions))
+                ),
+                None => "You haven't answered any questions yet. Use /question to start a quiz!".to_string(),
+            };
+            drop(scores);
+
+            bot.send_message(msg.chat.id, text).await?;
+        }
+        Command::Badges => {
+            let user_id = msg.from().map_or(msg.chat.id.0, |u| u.id.0 as i64);
+            let text = format_badges(&state.achievements, state.user_scores.lock().await.get(&user_id));
+            bot.send_message(msg.chat.id, text).await?;
+        }
         Command::Help => {
             bot.send_message(msg.chat.id, Command::descriptions().to_string())
                 .await?;
@@ -93,38 +173,151 @@ pub async fn command_handler(
         Command::Preferences => {
             handle_preferences(bot, msg, state).await?;
         }
+        Command::Schedule(args) => {
+            handle_schedule(bot, msg, args, state).await?;
+        }
     }
     Ok(())
 }
 
-// Helper function to start a new quiz
-async fn start_new_quiz(
+/// One line per category in the question bank with the user's mastery, accuracy
+/// and points there.
+pub fn format_category_mastery(user_score: &UserScore, categories: &[(String, usize)]) -> String {
+    categories
+        .iter()
+        .map(|(category, count)| match user_score.categories.get(category) {
+            Some(stats) if stats.answered > 0 => format!(
+                "{}: {}% mastered, {}% accuracy, {} points",
+                category,
+                stats.mastery(*count),
+                stats.accuracy(),
+                stats.points
+            ),
+            _ => format!("{}: not started", category),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Splits "/theme the great masjid 10" into the category and an optional quiz length
+fn parse_theme_args(args: &str) -> (String, Option<u32>) {
+    let args = args.trim();
+    if let Some((category, last)) = args.rsplit_once(char::is_whitespace) {
+        if let Ok(length) = last.parse::<u32>() {
+            return (category.trim().to_string(), Some(length));
+        }
+    }
+    (args.to_string(), None)
+}
+
+// Reads "/question hadith hard 10 30s" on top of the user's defaults. Numbers set the
+// length, "30s" a per-question time limit ("untimed" clears it), easy/medium/hard
+// the difficulty, "any" clears the category and everything else is matched
+// against the categories.
+pub fn parse_quiz_args(
+    args: &str,
+    defaults: QuizSettings,
+    categories: &[(String, usize)],
+) -> Result<QuizSettings, String> {
+    let mut settings = defaults;
+    let mut category_words = Vec::new();
+
+    for word in args.split_whitespace() {
+        if let Ok(length) = word.parse::<u32>() {
+            settings = settings.with_length(length);
+        } else if let Some(difficulty) = Difficulty::parse(word) {
+            settings.difficulty = Some(difficulty);
+            settings.adaptive = false;
+        } else if word.eq_ignore_ascii_case("adaptive") {
+            settings.adaptive = true;
+        } else if word.eq_ignore_ascii_case("untimed") {
+            settings.time_limit_secs = None;
+        } else if let Some(secs) = word.strip_suffix('s').and_then(|secs| secs.parse::<u32>().ok()) {
+            settings = settings.with_time_limit(Some(secs));
+        } else if word.eq_ignore_ascii_case("any") || word.eq_ignore_ascii_case("all") {
+            settings.category = None;
+        } else {
+            category_words.push(word);
+        }
+    }
+
+    if !category_words.is_empty() {
+        let wanted = category_words.join(" ");
+        let names: Vec<&str> = categories.iter().map(|(c, _)| c.as_str()).collect();
+        match match_category(&wanted, &names) {
+            Some(category) => settings.category = Some(category.to_string()),
+            None => {
+                return Err(format!(
+                    "No category matches \"{}\". Available categories: {}",
+                    wanted,
+                    names.join(", ")
+                ))
+            }
+        }
+    }
+
+    Ok(settings)
+}
+
+// Helper function to start a new quiz from the given settings
+pub async fn start_new_quiz(
     bot: Bot,
     chat_id: ChatId,
-    max_questions: u32,
+    user_id: i64,
+    settings: &QuizSettings,
     state: Arc<BotState>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let question = {
-        let mut rng = state.rng.lock().await;
-        state.questions.iter().choose(&mut *rng).unwrap().clone()
+    let difficulty = if settings.adaptive {
+        Some(state.adaptive_difficulty(user_id).await)
+    } else {
+        settings.difficulty
     };
 
+    let count = settings.length as usize;
+    let category = settings.category.as_deref();
+    let mut question_ids = state.deal_questions(user_id, category, difficulty, count).await;
+    if question_ids.is_empty() && settings.adaptive {
+        // Nothing tagged at this level yet, so fall back to any difficulty
+        question_ids = state.deal_questions(user_id, category, None, count).await;
+    }
+    if question_ids.is_empty() {
+        bot.send_message(chat_id, "No questions match your quiz settings! Try /settings to pick something else.")
+            .await?;
+        return Ok(());
+    }
+    let max_questions = question_ids.len() as u32;
+    let question = match state.question(question_ids.remove(0)) {
+        Some(question) => question.clone(),
+        None => return Ok(()),
+    };
+
+    let header = match difficulty {
+        Some(difficulty) if settings.adaptive => format!("🎯 Adaptive difficulty: {}\n\n", difficulty),
+        _ => String::new(),
+    };
+
+    let quiz_id = state.new_quiz_id().await;
     let sent_message = bot
-        .send_message(chat_id, &format!("Question 1/{}\n\n{}", max_questions, question.question))
-        .reply_markup(create_keyboard(&question, None, false, true))
+        .send_message(chat_id, format!("{}Question 1/{}\n\n{}", header, max_questions, question.question))
+        .reply_markup(create_keyboard(&question, quiz_id, None, false, true))
         .await?;
 
-    state.active_questions.lock().await.insert(
-        chat_id.0,
-        ActiveQuestion {
-            question,
-            message_id: sent_message.id,
-            game_state: GameState::InProgress {
-                questions_asked: 1,
-                max_questions,
-            },
+    let mut active_question = ActiveQuestion::new(
+        quiz_id,
+        question.id,
+        sent_message.id,
+        GameState::InProgress {
+            questions_asked: 1,
+            max_questions,
         },
     );
+    active_question.remaining_questions = question_ids;
+    active_question.time_limit = settings.time_limit();
+    active_question.adaptive = settings.adaptive;
+
+    schedule_question_timeout(bot.clone(), chat_id, &active_question, state.clone());
+    state.active_questions.lock().await.insert(chat_id.0, active_question);
+    state.mark_quizzes_dirty();
 
     Ok(())
 }
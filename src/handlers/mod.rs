@@ -1,7 +1,11 @@
 mod command;
 mod callback;
 mod reminder;
+mod settings;
+mod timer;
 
 pub use command::*;
 pub use callback::*;
-pub use reminder::*;
\ No newline at end of file
+pub use reminder::*;
+pub use settings::*;
+pub use timer::*;
\ No newline at end of file
@@ -1,10 +1,15 @@
 
 use crate::BotState;
-use chrono::{Datelike, Weekday, Utc};
+use chrono::{DateTime, Utc};
 use std::sync::Arc;
 use teloxide::prelude::*;
-use tokio::time::{timeout, interval, Duration};
-use crate::types::UserReminderPreferences;
+use tokio::time::{interval, Duration};
+use crate::delivery::DeliveryQueue;
+use crate::scheduler::{CronRule, Scheduler};
+use crate::keyboard::{create_subscriptions_keyboard, SubscriptionCallbackData, SubscriptionChange};
+use crate::types::{QuietHours, ReminderSchedule, ReminderStream, ReminderTemplate, ReminderTemplateAct, UserReminderPreferences, MAX_DELIVERY_TIMES, MIN_CRON_INTERVAL, NAMED_DELIVERY_TIMES};
+use chrono::{NaiveTime, TimeDelta};
+use chrono_tz::Tz;
 use std::error::Error;
 
 pub async fn handle_opt_out(
@@ -13,17 +18,23 @@ pub async fn handle_opt_out(
     state: Arc<BotState>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut preferences = state.user_preferences.lock().await;
-    if let Some(prefs) = preferences.get_mut(&msg.chat.id.0) {
-        prefs.opted_in = false;
-        
+    let opted_out = match preferences.get_mut(&msg.chat.id.0) {
+        Some(prefs) => {
+            prefs.opted_in = false;
+            true
+        }
+        None => false,
+    };
+    drop(preferences);
+
+    if opted_out {
+        state.mark_preferences_dirty();
         bot.send_message(
             msg.chat.id,
             "✅ You've successfully opted out of reminders. Use /optin anytime to start receiving them again.",
         )
         .await?;
     }
-
-    state.save_preferences().await?;
     Ok(())
 }
 
@@ -52,7 +63,7 @@ pub async fn handle_opt_in(
     log::info!("Lock acquired successfully");
     
     // Wrap the critical section in a block to ensure the lock is released as soon as possible
-    {
+    let (text, keyboard) = {
         let prefs = preferences.entry(msg.chat.id.0).or_insert_with(|| {
             UserReminderPreferences::new(
                 msg.chat.id.0,
@@ -60,66 +71,73 @@ pub async fn handle_opt_in(
             )
         });
         prefs.opted_in = true;
-    }
+        // Picked up from the schedule on the next reminder check
+        prefs.next_reminder = None;
+        // Start each series from its first template rather than part way through
+        prefs.subscriptions.restart_series();
+        (opt_in_text(prefs), create_subscriptions_keyboard(&prefs.subscriptions))
+    };
     
     // Release the lock before sending the message and saving preferences
     drop(preferences);
     
     log::info!("Sending confirmation message");
-    bot.send_message(
-        msg.chat.id,
-        "✅ You've successfully opted in to receive daily sunnah reminders! You'll receive the same reminder 4times daily for a week.",
-    )
-    .await?;
-
-    log::info!("Saving preferences");
-    // Add timeout to save_preferences as well
-    match timeout(Duration::from_secs(5), state.save_preferences()).await {
-        Ok(result) => {
-            if let Err(e) = result {
-                log::error!("Error saving preferences: {}", e);
-                bot.send_message(
-                    msg.chat.id,
-                    "Warning: There was an issue saving your preferences. Your settings might not persist after bot restart.",
-                )
-                .await?;
-            }
-        }
-        Err(_) => {
-            log::error!("Timeout while saving preferences");
-            bot.send_message(
-                msg.chat.id,
-                "Warning: Saving preferences timed out. Your settings might not persist after bot restart.",
-            )
-            .await?;
-        }
-    }
+    bot.send_message(msg.chat.id, text)
+        .reply_markup(keyboard)
+        .await?;
+
+    state.mark_preferences_dirty();
 
     log::info!("Opt-in handling completed successfully");
     Ok(())
 }
 
+/// Confirms an opt-in with what the user will actually get: their subscriptions
+/// and when reminders are sent.
+pub fn opt_in_text(prefs: &UserReminderPreferences) -> String {
+    format!("✅ You've successfully opted in to reminders!\n\n{}", preferences_text(prefs))
+}
+
+fn preferences_text(prefs: &UserReminderPreferences) -> String {
+    let status = if prefs.opted_in { "opted in" } else { "opted out" };
+    let subscriptions: Vec<String> = ReminderStream::ALL
+        .iter()
+        .map(|&stream| {
+            let subscription = prefs.subscriptions.get(stream);
+            if subscription.enabled {
+                format!("{}: {}", stream.label(), subscription.frequency.label())
+            } else {
+                format!("{}: Off", stream.label())
+            }
+        })
+        .collect();
+
+    format!(
+        "Your reminder preferences:\nStatus: {}\nLast reminder: {}\n{}\n\n{}\n\nTap below to choose which reminders you get and how often.",
+        status,
+        prefs.last_reminder
+            .map(|dt| dt.with_timezone(&prefs.schedule.timezone).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "Never".to_string()),
+        subscriptions.join("\n"),
+        format_schedule(&prefs.schedule, prefs.opted_in.then(|| prefs.schedule.next_due(Utc::now())).flatten())
+    )
+}
+
 pub async fn handle_preferences(
     bot: Bot,
     msg: Message,
     state: Arc<BotState>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let preferences = state.user_preferences.lock().await;
-    
-    if let Some(prefs) = preferences.get(&msg.chat.id.0) {
-        let status = if prefs.opted_in { "opted in" } else { "opted out" };
-        
-        bot.send_message(
-            msg.chat.id,
-            format!(
-                "Your reminder preferences:\nStatus: {}\nLast reminder: {}", 
-                status,
-                prefs.last_reminder
-                    .map(|dt| dt.format("%Y-%m-%d %H:%M:%S UTC").to_string())
-                    .unwrap_or_else(|| "Never".to_string())
-            ),
-        )
-        .await?;
+    let reply = preferences
+        .get(&msg.chat.id.0)
+        .map(|prefs| (preferences_text(prefs), create_subscriptions_keyboard(&prefs.subscriptions)));
+    drop(preferences);
+
+    if let Some((text, keyboard)) = reply {
+        bot.send_message(msg.chat.id, text)
+            .reply_markup(keyboard)
+            .await?;
     } else {
         bot.send_message(
             msg.chat.id,
@@ -130,185 +148,386 @@ pub async fn handle_preferences(
     Ok(())
 }
 
+pub async fn handle_subscription_callback(
+    bot: Bot,
+    query_id: String,
+    message: &Message,
+    username: &str,
+    payload: SubscriptionCallbackData,
+    state: Arc<BotState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut preferences = state.user_preferences.lock().await;
+    let prefs = preferences.entry(message.chat.id.0).or_insert_with(|| {
+        UserReminderPreferences::new(message.chat.id.0, username.to_string())
+    });
+    let before = prefs.subscriptions.clone();
+    match payload.change {
+        SubscriptionChange::Toggle(stream) => {
+            let subscription = prefs.subscriptions.get_mut(stream);
+            subscription.enabled = !subscription.enabled;
+        }
+        SubscriptionChange::Frequency(stream, frequency) => {
+            prefs.subscriptions.get_mut(stream).frequency = frequency;
+        }
+    }
+    let changed = prefs.subscriptions != before;
+    let reply = (preferences_text(prefs), create_subscriptions_keyboard(&prefs.subscriptions));
+    drop(preferences);
 
+    // Telegram rejects edits that don't change anything
+    if changed {
+        state.mark_preferences_dirty();
+        let (text, keyboard) = reply;
+        bot.edit_message_text(message.chat.id, message.id, text)
+            .reply_markup(keyboard)
+            .await?;
+    }
 
-pub async fn start_reminder_sender(bot: Bot, state: Arc<BotState>) {
-    let mut template_sender_id = 0;
-    let mut template_sender_act_id = 0;
-
-    let mut interval = interval(Duration::from_secs(60)); // 60sec interval check
-    // let mut next_send_time = Utc::now() + Duration::from_secs(5);
-    let mut last_monday_check = Utc::now().date_naive();
+    bot.answer_callback_query(query_id).text("Preferences saved").await?;
+    Ok(())
+}
 
-    let mut next_send_time_act = Utc::now() + Duration::from_secs(10); 
-    let mut last_check_date = Utc::now().date_naive();
 
-    loop {
-        let now = Utc::now();
 
-        log::info!("loop");
-
-        if now >= next_send_time_act {
-            send_reminders(&bot, &state, template_sender_act_id, false).await;
-            log::info!("send rem 1");
+#[derive(Debug, PartialEq)]
+pub enum ScheduleChange {
+    Timezone(Tz),
+    DeliveryTimes(Vec<NaiveTime>),
+    QuietHours(Option<QuietHours>),
+    Cron(Option<CronRule>),
+}
 
-            send_reminders(&bot, &state, template_sender_id, true).await;
-            log::info!("send rem 2");
+fn parse_time(word: &str) -> Option<NaiveTime> {
+    let word = word.to_lowercase();
+    NAMED_DELIVERY_TIMES
+        .iter()
+        .find(|(name, _, _)| *name == word)
+        .and_then(|(_, h, m)| NaiveTime::from_hms_opt(*h, *m, 0))
+        .or_else(|| NaiveTime::parse_from_str(&word, "%H:%M").ok())
+}
 
-            next_send_time_act = now + Duration::from_secs(21600); // Update next send time (4hrs)
+// Shortest gap between sorted times of day, including from the last one to the
+// first one the next day
+fn shortest_gap(times: &[NaiveTime]) -> TimeDelta {
+    let overnight = match (times.first(), times.last()) {
+        (Some(first), Some(last)) => *first - *last + TimeDelta::days(1),
+        _ => TimeDelta::days(1),
+    };
+    times.windows(2).map(|pair| pair[1] - pair[0]).fold(overnight, TimeDelta::min)
+}
 
-            if now.date_naive() != last_check_date {
-                template_sender_act_id += 1;
-                last_check_date = now.date_naive(); 
-                log::info!("Monday detected: Incremented template_sender_id to {}", template_sender_act_id);
+/// Parses `/schedule` arguments. No arguments means show the schedule; errors
+/// are ready to send back to the user.
+pub fn parse_schedule_args(args: &str) -> Result<Option<ScheduleChange>, String> {
+    let mut words = args.split_whitespace();
+    let Some(setting) = words.next() else {
+        return Ok(None);
+    };
+    let values: Vec<&str> = words.collect();
+
+    match setting.to_lowercase().as_str() {
+        "tz" | "timezone" => {
+            let [name] = values.as_slice() else {
+                return Err("Give one timezone, e.g. /schedule tz Africa/Lagos".to_string());
+            };
+            name.parse::<Tz>()
+                .map(|tz| Some(ScheduleChange::Timezone(tz)))
+                .map_err(|_| format!("Unknown timezone {:?}. Use a name like Europe/London or Asia/Jakarta.", name))
+        }
+        "times" => {
+            if values.is_empty() || values.len() > MAX_DELIVERY_TIMES {
+                return Err(format!("Give between 1 and {} times, e.g. /schedule times early midday 21:00", MAX_DELIVERY_TIMES));
             }
-
-            if now.weekday() == Weekday::Mon && now.date_naive() != last_monday_check {
-                template_sender_id += 1;
-                last_monday_check = now.date_naive(); // Update the last check date
-                log::info!("Monday detected: Incremented template_sender_id to {}", template_sender_id);
+            let mut times = values
+                .iter()
+                .map(|word| parse_time(word).ok_or_else(|| format!("Couldn't read the time {:?}. Use HH:MM or a name like early.", word)))
+                .collect::<Result<Vec<_>, _>>()?;
+            times.sort();
+            times.dedup();
+            if shortest_gap(&times) < MIN_CRON_INTERVAL {
+                return Err(format!(
+                    "Reminders can be at most once every {} minutes, e.g. /schedule times early midday 21:00",
+                    MIN_CRON_INTERVAL.num_minutes()
+                ));
             }
+            Ok(Some(ScheduleChange::DeliveryTimes(times)))
         }
-
-        // if now >= next_send_time {
-        //     send_reminders(&bot, &state, template_sender_id, true).await;
-        //     log::info!("send rem 2");
-        //     next_send_time = now + Duration::from_secs(21600); // Update next send time (4hrs)
-
-        //     if now.weekday() == Weekday::Mon && now.date_naive() != last_monday_check {
-        //         template_sender_id += 1;
-        //         last_monday_check = now.date_naive(); // Update the last check date
-        //         log::info!("Monday detected: Incremented template_sender_id to {}", template_sender_id);
-        //     }
-        // }
-        interval.tick().await;
+        "quiet" => match values.as_slice() {
+            ["off"] => Ok(Some(ScheduleChange::QuietHours(None))),
+            [span] => {
+                let (start, end) = span
+                    .split_once('-')
+                    .and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?)))
+                    .filter(|(start, end)| start != end)
+                    .ok_or_else(|| "Give quiet hours as a span, e.g. /schedule quiet 22:00-07:00".to_string())?;
+                Ok(Some(ScheduleChange::QuietHours(Some(QuietHours { start, end }))))
+            }
+            _ => Err("Give quiet hours as a span, e.g. /schedule quiet 22:00-07:00, or /schedule quiet off".to_string()),
+        },
+        "cron" => match values.as_slice() {
+            ["off"] => Ok(Some(ScheduleChange::Cron(None))),
+            [] => Err("Give a cron rule, e.g. /schedule cron 0 6,18 * * 1-5, or /schedule cron off".to_string()),
+            fields => {
+                let rule = CronRule::parse(&fields.join(" "))?;
+                if rule.min_interval() < MIN_CRON_INTERVAL {
+                    return Err(format!(
+                        "Reminders can be at most once every {} minutes, e.g. /schedule cron 0 6,18 * * *",
+                        MIN_CRON_INTERVAL.num_minutes()
+                    ));
+                }
+                Ok(Some(ScheduleChange::Cron(Some(rule))))
+            }
+        },
+        _ => Err("Unknown setting. Use tz, times, quiet or cron.".to_string()),
     }
 }
 
-async fn send_reminders(bot: &Bot, state: &Arc<BotState>, template_sender_id: usize, is_template: bool) {
-    let preferences = match state.acquire_preferences_lock().await {
+pub fn format_schedule(schedule: &ReminderSchedule, next_reminder: Option<DateTime<Utc>>) -> String {
+    let times = match &schedule.cron {
+        Some(cron) => format!("cron {}", cron),
+        None => schedule.delivery_times.iter().map(|t| t.format("%H:%M").to_string()).collect::<Vec<_>>().join(", "),
+    };
+    let quiet = schedule
+        .quiet_hours
+        .map(|q| format!("{}-{}", q.start.format("%H:%M"), q.end.format("%H:%M")))
+        .unwrap_or_else(|| "None".to_string());
+    let next = next_reminder
+        .map(|dt| dt.with_timezone(&schedule.timezone).format("%a %H:%M").to_string())
+        .unwrap_or_else(|| "None scheduled".to_string());
+
+    format!(
+        "🕰 Reminder schedule\n\
+        Timezone: {}\n\
+        Delivery times: {}\n\
+        Quiet hours: {}\n\
+        Next reminder: {}\n\n\
+        Change it with:\n\
+        /schedule tz Africa/Lagos\n\
+        /schedule times early midday evening (or HH:MM)\n\
+        /schedule quiet 22:00-07:00 (or off)\n\
+        /schedule cron 0 6,18 * * 1-5 (or off)",
+        schedule.timezone.name(),
+        times,
+        quiet,
+        next
+    )
+}
+
+pub async fn handle_schedule(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    state: Arc<BotState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let change = match parse_schedule_args(&args) {
+        Ok(change) => change,
+        Err(e) => {
+            bot.send_message(msg.chat.id, format!("⚠️ {}", e)).await?;
+            return Ok(());
+        }
+    };
+
+    let mut preferences = match state.acquire_preferences_lock().await {
         Ok(guard) => guard,
         Err(e) => {
-            log::error!("Failed to acquire lock in reminder sender: {}", e);
-            return;
+            log::error!("Failed to acquire preferences lock: {}", e);
+            bot.send_message(msg.chat.id, "Sorry, the system is busy. Please try again in a few moments.").await?;
+            return Ok(());
         }
     };
+    let prefs = preferences.entry(msg.chat.id.0).or_insert_with(|| {
+        UserReminderPreferences::new(
+            msg.chat.id.0,
+            msg.from().map_or("Unknown".to_string(), |u| u.first_name.clone())
+        )
+    });
+
+    let changed = change.is_some();
+    match change {
+        Some(ScheduleChange::Timezone(tz)) => prefs.schedule.timezone = tz,
+        Some(ScheduleChange::DeliveryTimes(times)) => {
+            prefs.schedule.delivery_times = times;
+            prefs.schedule.cron = None;
+        }
+        Some(ScheduleChange::QuietHours(quiet)) => prefs.schedule.quiet_hours = quiet,
+        Some(ScheduleChange::Cron(cron)) => prefs.schedule.cron = cron,
+        None => {}
+    }
+    if changed {
+        prefs.next_reminder = prefs.schedule.next_due(Utc::now());
+    }
 
-    let now = Utc::now();
+    let next_reminder = prefs.opted_in.then(|| prefs.schedule.next_due(Utc::now())).flatten();
+    let mut text = format_schedule(&prefs.schedule, next_reminder);
+    if !prefs.opted_in {
+        text.push_str("\n\nYou're not receiving reminders. Use /optin to start.");
+    }
+    drop(preferences);
 
-    for (user_id, prefs) in preferences.iter() {
-        if !prefs.opted_in {
-            continue;
-        }
+    if changed {
+        state.mark_preferences_dirty();
+        text.insert_str(0, "✅ Schedule updated.\n\n");
+    }
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
 
-        // Check if at least 1 minute has passed since last reminder
-        // if let Some(last_reminder) = prefs.last_reminder {
-        //     if (now - last_reminder).num_minutes() < 1 {
-        //         continue;
-        //     }
-        // }
-
-        // Use the thread-safe RNG instance
-        if is_template == true {
-            if let Some(template) = state.reminder_templates.get(template_sender_id) {
-                fn escape_markdown_v2(text: &str) -> String {
-                    text.chars()
-                        .map(|c| match c {
-                            '_' | '*' | '[' | ']' | '(' | ')' | '~' | '`' | '>' | '#' | '+' | '-' | '=' | '|' | '{' | '}' | '.' | '!' => format!("\\{}", c),
-                            _ => c.to_string(),
-                        })
-                        .collect()
-                }
-    
-                let full_message = format!(
-                    "❁❀❁❀ 🌅 *Reminder* 🕌 ❀❁❀❁\n\
-                    ━━━━━━━━━━━━━━━━━━━━━\n\
-                    *{}* \n\n\
-                    ✨ *𝒜𝓇𝒶𝒷𝒾𝒸 𝒯𝑒𝓍𝓉:*\n\
-                    `{}`\n\n\
-                    🌟  *𝒯𝓇𝒶𝓃𝓈𝓁𝒾𝓉𝑒𝓇𝒶𝓉𝒾𝑜𝓃:*\n\
-                    `{}`\n\n\
-                    🔤 *𝒯𝓇𝒶𝓃𝓈𝓁𝒶𝓉𝒾𝑜𝓃:*\n\
-                    `{}`\n\n\
-                    📚 *𝑅𝑒𝒻𝑒𝓇𝑒𝓃𝒸𝑒:*\n\
-                    `{}`\n\n\
-                    ━━━━━━━━━━━━━━━━━━━━━",
-                     &template.message,  
-                     &template.arabic, 
-                     escape_markdown_v2(&template.transliteration),  
-                     escape_markdown_v2(&template.translation), 
-                     escape_markdown_v2(&template.reference)
-                );
-                
-                if let Err(e) = bot
-                    .send_message(ChatId(*user_id), &full_message)
-                    .parse_mode(teloxide::types::ParseMode::MarkdownV2)
-                    .await
-                {
-                    log::error!("Failed to send reminder to user {}: {}", user_id, e);
-                    continue;
-                }
-            }
-        }
+pub async fn start_reminder_sender(bot: Bot, state: Arc<BotState>, scheduler: Scheduler) {
+    let mut interval = interval(Duration::from_secs(60)); // 60sec interval check
+    // Shared by both streams so together they stay under Telegram's rate limit
+    let mut queue = DeliveryQueue::default();
 
-        if is_template == false {
-            if let Some(template) = state.reminder_templates_act.get(template_sender_id) {
-                fn escape_markdown_v2(text: &str) -> String {
-                    text.chars()
-                        .map(|c| match c {
-                            '_' | '*' | '[' | ']' | '(' | ')' | '~' | '`' | '>' | '#' | '+' | '-' | '=' | '|' | '{' | '}' | '.' | '!' => format!("\\{}", c),
-                            _ => c.to_string(),
-                        })
-                        .collect()
-                }
-    
-                let full_message = format!(
-                    "❁❀❁❀ 🌅 *Reminder* 🕌 ❀❁❀❁\n\
-                    ━━━━━━━━━━━━━━━━━━━━━\n\
-                    *{}* \n\n\
-                    🔤 *𝒜𝒸𝓉:*\n\
-                    `{}`\n\n\
-                    📚 *𝑅𝑒𝒻𝑒𝓇𝑒𝓃𝒸𝑒:*\n\
-                    `{}`\n\n\
-                    ━━━━━━━━━━━━━━━━━━━━━",
-                     &template.message,  
-                     &template.act, 
-                     escape_markdown_v2(&template.reference)
-                );
-                
-                if let Err(e) = bot
-                    .send_message(ChatId(*user_id), &full_message)
-                    .parse_mode(teloxide::types::ParseMode::MarkdownV2)
-                    .await
-                {
-                    log::error!("Failed to send reminder to user {}: {}", user_id, e);
-                    continue;
-                }
-            }
+    loop {
+        let due = take_due_reminders(&state, &scheduler).await;
+        let deliveries = |stream| -> Vec<(i64, usize)> {
+            due.iter()
+                .flat_map(|(user_id, templates)| {
+                    templates.iter().filter(|(s, _)| *s == stream).map(move |(_, index)| (*user_id, *index))
+                })
+                .collect()
+        };
+        if !due.is_empty() {
+            log::info!("Sending reminders to {} users", due.len());
+            send_reminders(&bot, &state, &mut queue, &deliveries(ReminderStream::SunnahActs), false).await;
+            send_reminders(&bot, &state, &mut queue, &deliveries(ReminderStream::Duas), true).await;
         }
 
-        
+        interval.tick().await;
     }
+}
 
-    drop(preferences);
-
+// Each user is due at their own local delivery times, for the streams they
+// subscribe to, at their own place in each series
+async fn take_due_reminders(state: &Arc<BotState>, scheduler: &Scheduler) -> Vec<(i64, Vec<(ReminderStream, usize)>)> {
     let mut preferences = match state.acquire_preferences_lock().await {
         Ok(guard) => guard,
         Err(e) => {
-            log::error!("Failed to acquire lock for updating reminder times: {}", e);
-            return;
+            log::error!("Failed to acquire lock in reminder sender: {}", e);
+            return Vec::new();
         }
     };
 
-    for (_, prefs) in preferences.iter_mut() {
-        if prefs.opted_in {
-            prefs.last_reminder = Some(now);
+    let series_len = |stream| match stream {
+        ReminderStream::Duas => state.reminder_templates.len(),
+        ReminderStream::SunnahActs => state.reminder_templates_act.len(),
+    };
+
+    let mut due = Vec::new();
+    let mut changed = false;
+    for (user_id, prefs) in preferences.iter_mut() {
+        let next_reminder = prefs.next_reminder;
+        let templates: Vec<(ReminderStream, usize)> = prefs
+            .take_due(scheduler)
+            .into_iter()
+            .filter_map(|(run, stream)| Some((stream, prefs.template_position(stream, run, series_len(stream))?)))
+            .collect();
+        if !templates.is_empty() {
+            due.push((*user_id, templates));
         }
+        changed |= prefs.next_reminder != next_reminder;
     }
-
     drop(preferences);
 
-    if let Err(_) = tokio::time::timeout(Duration::from_secs(5), state.save_preferences()).await {
-        log::error!("Timeout while saving preferences in reminder sender");
+    if changed {
+        state.mark_preferences_dirty();
     }
-}
\ No newline at end of file
+    due
+}
+
+fn escape_markdown_v2(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '_' | '*' | '[' | ']' | '(' | ')' | '~' | '`' | '>' | '#' | '+' | '-' | '=' | '|' | '{' | '}' | '.' | '!' | '\\' => format!("\\{}", c),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+// Inside a code span only these two end or break it
+fn escape_code_v2(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '`' | '\\' => format!("\\{}", c),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+pub fn format_dua_reminder(template: &ReminderTemplate) -> String {
+    format!(
+        "❁❀❁❀ 🌅 *Reminder* 🕌 ❀❁❀❁\n\
+        ━━━━━━━━━━━━━━━━━━━━━\n\
+        *{}* \n\n\
+        ✨ *𝒜𝓇𝒶𝒷𝒾𝒸 𝒯𝑒𝓍𝓉:*\n\
+        `{}`\n\n\
+        🌟  *𝒯𝓇𝒶𝓃𝓈𝓁𝒾𝓉𝑒𝓇𝒶𝓉𝒾𝑜𝓃:*\n\
+        `{}`\n\n\
+        🔤 *𝒯𝓇𝒶𝓃𝓈𝓁𝒶𝓉𝒾𝑜𝓃:*\n\
+        `{}`\n\n\
+        📚 *𝑅𝑒𝒻𝑒𝓇𝑒𝓃𝒸𝑒:*\n\
+        `{}`\n\n\
+        ━━━━━━━━━━━━━━━━━━━━━",
+         escape_markdown_v2(&template.message),
+         escape_code_v2(&template.arabic),
+         escape_markdown_v2(&template.transliteration),
+         escape_markdown_v2(&template.translation),
+         escape_markdown_v2(&template.reference)
+    )
+}
+
+pub fn format_act_reminder(template: &ReminderTemplateAct) -> String {
+    format!(
+        "❁❀❁❀ 🌅 *Reminder* 🕌 ❀❁❀❁\n\
+        ━━━━━━━━━━━━━━━━━━━━━\n\
+        *{}* \n\n\
+        🔤 *𝒜𝒸𝓉:*\n\
+        `{}`\n\n\
+        📚 *𝑅𝑒𝒻𝑒𝓇𝑒𝓃𝒸𝑒:*\n\
+        `{}`\n\n\
+        ━━━━━━━━━━━━━━━━━━━━━",
+         escape_markdown_v2(&template.message),
+         escape_code_v2(&template.act),
+         escape_markdown_v2(&template.reference)
+    )
+}
+
+// Messages are built up front so no lock is held while sending
+async fn send_reminders(bot: &Bot, state: &Arc<BotState>, queue: &mut DeliveryQueue, deliveries: &[(i64, usize)], is_template: bool) {
+    let messages: Vec<(ChatId, String)> = deliveries
+        .iter()
+        .filter_map(|(user_id, template_index)| {
+            let text = if is_template {
+                format_dua_reminder(state.reminder_templates.get(*template_index)?)
+            } else {
+                format_act_reminder(state.reminder_templates_act.get(*template_index)?)
+            };
+            Some((ChatId(*user_id), text))
+        })
+        .collect();
+    if messages.is_empty() {
+        return;
+    }
+
+    let report = queue
+        .broadcast(messages, |chat_id, text| async move {
+            bot.send_message(chat_id, text)
+                .parse_mode(teloxide::types::ParseMode::MarkdownV2)
+                .await
+                .map(|_| ())
+        })
+        .await;
+    let kind = if is_template { "Dua" } else { "Sunnah act" };
+    log::info!("{} reminders: {}", kind, report);
+
+    // Users who blocked the bot are opted out rather than retried every time
+    if !report.blocked.is_empty() {
+        let mut preferences = state.user_preferences.lock().await;
+        for chat_id in &report.blocked {
+            if let Some(prefs) = preferences.get_mut(&chat_id.0) {
+                prefs.opted_in = false;
+            }
+        }
+        drop(preferences);
+        state.mark_preferences_dirty();
+    }
+}
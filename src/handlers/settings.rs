@@ -0,0 +1,75 @@
+use crate::BotState;
+use crate::category::category_counts;
+use crate::handlers::start_new_quiz;
+use crate::keyboard::{create_settings_keyboard, SettingsCallbackData, SettingsChange};
+use crate::types::QuizSettings;
+use std::error::Error;
+use std::sync::Arc;
+use teloxide::prelude::*;
+
+fn settings_text(settings: &QuizSettings) -> String {
+    format!(
+        "⚙️ Your quiz settings:\n\n{}\n\nTap to change them, then start a quiz.",
+        settings.describe()
+    )
+}
+
+pub async fn handle_settings(
+    bot: Bot,
+    msg: Message,
+    state: Arc<BotState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let user_id = msg.from().map_or(msg.chat.id.0, |u| u.id.0 as i64);
+    let settings = state.quiz_settings(user_id).await;
+    let categories = category_counts(&state.questions);
+
+    bot.send_message(msg.chat.id, settings_text(&settings))
+        .reply_markup(create_settings_keyboard(&settings, &categories))
+        .await?;
+    Ok(())
+}
+
+pub async fn handle_settings_callback(
+    bot: Bot,
+    query_id: String,
+    message: &Message,
+    user_id: i64,
+    payload: SettingsCallbackData,
+    state: Arc<BotState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let current = state.quiz_settings(user_id).await;
+    let categories = category_counts(&state.questions);
+
+    let updated = match payload.change {
+        SettingsChange::Start => {
+            bot.answer_callback_query(query_id).await?;
+            return start_new_quiz(bot, message.chat.id, user_id, &current, state).await;
+        }
+        SettingsChange::Length(length) => current.clone().with_length(length),
+        SettingsChange::Category(None) => QuizSettings { category: None, ..current.clone() },
+        SettingsChange::Category(Some(index)) => match categories.get(index) {
+            Some((category, _)) => QuizSettings { category: Some(category.clone()), ..current.clone() },
+            None => {
+                bot.answer_callback_query(query_id)
+                    .text("That category is no longer available.")
+                    .await?;
+                return Ok(());
+            }
+        },
+        SettingsChange::Difficulty(difficulty) => QuizSettings { difficulty, adaptive: false, ..current.clone() },
+        SettingsChange::Adaptive => QuizSettings { adaptive: true, ..current.clone() },
+        SettingsChange::TimeLimit(secs) => current.clone().with_time_limit(secs),
+    };
+
+    // Telegram rejects edits that don't change anything
+    if updated != current {
+        state.set_quiz_settings(user_id, updated.clone()).await;
+
+        bot.edit_message_text(message.chat.id, message.id, settings_text(&updated))
+            .reply_markup(create_settings_keyboard(&updated, &categories))
+            .await?;
+    }
+
+    bot.answer_callback_query(query_id).text("Settings saved").await?;
+    Ok(())
+}
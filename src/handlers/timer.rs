@@ -0,0 +1,79 @@
+use crate::BotState;
+use crate::handlers::advance_quiz;
+use crate::keyboard::create_keyboard;
+use crate::types::ActiveQuestion;
+use std::future::Future;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use tokio::task::JoinHandle;
+
+/// Sleeps until `active_question`'s time limit runs out and, if it is still the
+/// current question in the chat by then, removes it and hands it to
+/// `on_expire`. Untimed questions get no timer.
+pub fn spawn_question_timer<F, Fut>(
+    state: Arc<BotState>,
+    chat_id: i64,
+    active_question: &ActiveQuestion,
+    on_expire: F,
+) -> Option<JoinHandle<()>>
+where
+    F: FnOnce(ActiveQuestion) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let deadline = active_question.asked_at + active_question.time_limit?;
+    let quiz_id = active_question.quiz_id;
+    let question_id = active_question.question_id;
+
+    Some(tokio::spawn(async move {
+        tokio::time::sleep_until(deadline).await;
+        if let Some(expired) = state.take_active_question(chat_id, quiz_id, question_id).await {
+            on_expire(expired).await;
+        }
+    }))
+}
+
+// Starts the countdown for a question that was just sent to the chat, or one
+// restored after a restart
+pub fn schedule_question_timeout(bot: Bot, chat_id: ChatId, active_question: &ActiveQuestion, state: Arc<BotState>) {
+    let timer_state = state.clone();
+    spawn_question_timer(timer_state, chat_id.0, active_question, move |expired| async move {
+        if let Err(e) = handle_question_timeout(bot, chat_id, expired, state).await {
+            log::error!("Failed to expire question in chat {}: {}", chat_id, e);
+        }
+    });
+}
+
+async fn handle_question_timeout(
+    bot: Bot,
+    chat_id: ChatId,
+    expired: ActiveQuestion,
+    state: Arc<BotState>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(question) = state.question(expired.question_id) {
+        bot.edit_message_reply_markup(chat_id, expired.message_id)
+            .reply_markup(create_keyboard(question, expired.quiz_id, Some(&question.correct_answer), true, true))
+            .await?;
+        bot.send_message(
+            chat_id,
+            format!("⏰ Time's up! The correct answer was: {}", question.correct_answer),
+        )
+        .await?;
+    }
+
+    advance_quiz(&bot, chat_id, expired, &state).await
+}
+
+/// Restarts the countdowns of quizzes restored after a restart. Questions whose
+/// time ran out while the bot was down expire straight away.
+pub async fn resume_question_timers(bot: Bot, state: Arc<BotState>) {
+    let active: Vec<(i64, ActiveQuestion)> = state
+        .active_questions
+        .lock()
+        .await
+        .iter()
+        .map(|(chat_id, active)| (*chat_id, active.clone()))
+        .collect();
+    for (chat_id, active) in active {
+        schedule_question_timeout(bot.clone(), ChatId(chat_id), &active, state.clone());
+    }
+}
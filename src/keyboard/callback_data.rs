@@ -0,0 +1,318 @@
+use crate::error::CallbackDataError;
+use crate::types::{Difficulty, ReminderFrequency, ReminderStream};
+
+// Bump whenever the layout below changes so buttons on old messages are rejected
+pub const CALLBACK_DATA_VERSION: u8 = 1;
+
+// Telegram refuses callback data longer than 64 bytes
+pub const MAX_CALLBACK_DATA_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuizAction {
+    Answer,
+    End,
+}
+
+impl QuizAction {
+    fn tag(self) -> &'static str {
+        match self {
+            QuizAction::Answer => "a",
+            QuizAction::End => "e",
+        }
+    }
+
+    fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "a" => Some(QuizAction::Answer),
+            "e" => Some(QuizAction::End),
+            _ => None,
+        }
+    }
+}
+
+/// Payload carried by quiz buttons, encoded as
+/// `<version>:<action>:<quiz id>:<question id>:<option index>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuizCallbackData {
+    pub version: u8,
+    pub quiz_id: u32,
+    pub question_id: u32,
+    pub option_index: u8,
+    pub action: QuizAction,
+}
+
+impl QuizCallbackData {
+    pub fn answer(quiz_id: u32, question_id: u32, option_index: u8) -> Self {
+        Self {
+            version: CALLBACK_DATA_VERSION,
+            quiz_id,
+            question_id,
+            option_index,
+            action: QuizAction::Answer,
+        }
+    }
+
+    pub fn end(quiz_id: u32, question_id: u32) -> Self {
+        Self {
+            version: CALLBACK_DATA_VERSION,
+            quiz_id,
+            question_id,
+            option_index: 0,
+            action: QuizAction::End,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}",
+            self.version,
+            self.action.tag(),
+            self.quiz_id,
+            self.question_id,
+            self.option_index
+        )
+    }
+
+    pub fn decode(data: &str) -> Result<Self, CallbackDataError> {
+        if data.len() > MAX_CALLBACK_DATA_LEN {
+            return Err(CallbackDataError::Malformed(data.to_string()));
+        }
+
+        let malformed = || CallbackDataError::Malformed(data.to_string());
+        let parts: Vec<&str> = data.split(':').collect();
+        if parts.len() != 5 {
+            return Err(malformed());
+        }
+
+        let version: u8 = parts[0].parse().map_err(|_| malformed())?;
+        if version != CALLBACK_DATA_VERSION {
+            return Err(CallbackDataError::UnsupportedVersion(version));
+        }
+
+        Ok(Self {
+            version,
+            action: QuizAction::from_tag(parts[1]).ok_or_else(malformed)?,
+            quiz_id: parts[2].parse().map_err(|_| malformed())?,
+            question_id: parts[3].parse().map_err(|_| malformed())?,
+            option_index: parts[4].parse().map_err(|_| malformed())?,
+        })
+    }
+}
+
+/// Payload carried by the category buttons of `/theme`, encoded as
+/// `<version>:t:<category index>`. The index is into `category_counts`, since
+/// a category name can be longer than Telegram allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThemeCallbackData {
+    pub version: u8,
+    pub category_index: usize,
+}
+
+impl ThemeCallbackData {
+    pub fn new(category_index: usize) -> Self {
+        Self {
+            version: CALLBACK_DATA_VERSION,
+            category_index,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}:t:{}", self.version, self.category_index)
+    }
+
+    pub fn decode(data: &str) -> Result<Self, CallbackDataError> {
+        if data.len() > MAX_CALLBACK_DATA_LEN {
+            return Err(CallbackDataError::Malformed(data.to_string()));
+        }
+
+        let malformed = || CallbackDataError::Malformed(data.to_string());
+        let parts: Vec<&str> = data.split(':').collect();
+        if parts.len() != 3 || parts[1] != "t" {
+            return Err(malformed());
+        }
+
+        let version: u8 = parts[0].parse().map_err(|_| malformed())?;
+        if version != CALLBACK_DATA_VERSION {
+            return Err(CallbackDataError::UnsupportedVersion(version));
+        }
+
+        Ok(Self {
+            version,
+            category_index: parts[2].parse().map_err(|_| malformed())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingsChange {
+    Length(u32),
+    // None means any category / any difficulty. Categories go by their index
+    // in `category_counts`, as with `/theme`
+    Category(Option<usize>),
+    Difficulty(Option<Difficulty>),
+    Adaptive,
+    // Seconds per question, None for untimed
+    TimeLimit(Option<u32>),
+    Start,
+}
+
+/// Payload carried by the `/settings` keyboard, encoded as
+/// `<version>:s:<field>:<value>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingsCallbackData {
+    pub version: u8,
+    pub change: SettingsChange,
+}
+
+impl SettingsCallbackData {
+    pub fn new(change: SettingsChange) -> Self {
+        Self {
+            version: CALLBACK_DATA_VERSION,
+            change,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let (field, value) = match &self.change {
+            SettingsChange::Length(length) => ("l", length.to_string()),
+            SettingsChange::Category(index) => ("c", index.map(|i| i.to_string()).unwrap_or_default()),
+            SettingsChange::Difficulty(difficulty) => {
+                ("d", difficulty.map(|d| d.to_string().to_lowercase()).unwrap_or_default())
+            }
+            SettingsChange::Adaptive => ("a", String::new()),
+            SettingsChange::TimeLimit(secs) => ("t", secs.map(|s| s.to_string()).unwrap_or_default()),
+            SettingsChange::Start => ("go", String::new()),
+        };
+        format!("{}:s:{}:{}", self.version, field, value)
+    }
+
+    pub fn decode(data: &str) -> Result<Self, CallbackDataError> {
+        if data.len() > MAX_CALLBACK_DATA_LEN {
+            return Err(CallbackDataError::Malformed(data.to_string()));
+        }
+
+        let malformed = || CallbackDataError::Malformed(data.to_string());
+        let parts: Vec<&str> = data.splitn(4, ':').collect();
+        if parts.len() != 4 || parts[1] != "s" {
+            return Err(malformed());
+        }
+
+        let version: u8 = parts[0].parse().map_err(|_| malformed())?;
+        if version != CALLBACK_DATA_VERSION {
+            return Err(CallbackDataError::UnsupportedVersion(version));
+        }
+
+        let value = parts[3];
+        let change = match parts[2] {
+            "l" => SettingsChange::Length(value.parse().map_err(|_| malformed())?),
+            "c" if value.is_empty() => SettingsChange::Category(None),
+            "c" => SettingsChange::Category(Some(value.parse().map_err(|_| malformed())?)),
+            "d" if value.is_empty() => SettingsChange::Difficulty(None),
+            "d" => SettingsChange::Difficulty(Some(Difficulty::parse(value).ok_or_else(malformed)?)),
+            "a" => SettingsChange::Adaptive,
+            "t" if value.is_empty() => SettingsChange::TimeLimit(None),
+            "t" => SettingsChange::TimeLimit(Some(value.parse().map_err(|_| malformed())?)),
+            "go" => SettingsChange::Start,
+            _ => return Err(malformed()),
+        };
+
+        Ok(Self { version, change })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionChange {
+    Toggle(ReminderStream),
+    Frequency(ReminderStream, ReminderFrequency),
+}
+
+/// Payload carried by the `/preferences` keyboard, encoded as
+/// `<version>:r:<stream>:<frequency, or "on" to toggle>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionCallbackData {
+    pub version: u8,
+    pub change: SubscriptionChange,
+}
+
+impl SubscriptionCallbackData {
+    pub fn new(change: SubscriptionChange) -> Self {
+        Self {
+            version: CALLBACK_DATA_VERSION,
+            change,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        fn stream_tag(stream: ReminderStream) -> &'static str {
+            match stream {
+                ReminderStream::Duas => "d",
+                ReminderStream::SunnahActs => "a",
+            }
+        }
+
+        let (stream, value) = match self.change {
+            SubscriptionChange::Toggle(stream) => (stream, "on"),
+            SubscriptionChange::Frequency(stream, frequency) => (
+                stream,
+                match frequency {
+                    ReminderFrequency::EveryDelivery => "e",
+                    ReminderFrequency::Daily => "d",
+                    ReminderFrequency::Weekly => "w",
+                },
+            ),
+        };
+        format!("{}:r:{}:{}", self.version, stream_tag(stream), value)
+    }
+
+    pub fn decode(data: &str) -> Result<Self, CallbackDataError> {
+        if data.len() > MAX_CALLBACK_DATA_LEN {
+            return Err(CallbackDataError::Malformed(data.to_string()));
+        }
+
+        let malformed = || CallbackDataError::Malformed(data.to_string());
+        let parts: Vec<&str> = data.split(':').collect();
+        if parts.len() != 4 || parts[1] != "r" {
+            return Err(malformed());
+        }
+
+        let version: u8 = parts[0].parse().map_err(|_| malformed())?;
+        if version != CALLBACK_DATA_VERSION {
+            return Err(CallbackDataError::UnsupportedVersion(version));
+        }
+
+        let stream = match parts[2] {
+            "d" => ReminderStream::Duas,
+            "a" => ReminderStream::SunnahActs,
+            _ => return Err(malformed()),
+        };
+        let change = match parts[3] {
+            "on" => SubscriptionChange::Toggle(stream),
+            "e" => SubscriptionChange::Frequency(stream, ReminderFrequency::EveryDelivery),
+            "d" => SubscriptionChange::Frequency(stream, ReminderFrequency::Daily),
+            "w" => SubscriptionChange::Frequency(stream, ReminderFrequency::Weekly),
+            _ => return Err(malformed()),
+        };
+
+        Ok(Self { version, change })
+    }
+}
+
+/// Any payload the bot puts on an inline button.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallbackData {
+    Quiz(QuizCallbackData),
+    Theme(ThemeCallbackData),
+    Settings(SettingsCallbackData),
+    Subscription(SubscriptionCallbackData),
+}
+
+impl CallbackData {
+    pub fn decode(data: &str) -> Result<Self, CallbackDataError> {
+        match data.split(':').nth(1) {
+            Some("t") => ThemeCallbackData::decode(data).map(CallbackData::Theme),
+            Some("s") => SettingsCallbackData::decode(data).map(CallbackData::Settings),
+            Some("r") => SubscriptionCallbackData::decode(data).map(CallbackData::Subscription),
+            _ => QuizCallbackData::decode(data).map(CallbackData::Quiz),
+        }
+    }
+}
@@ -1,8 +1,12 @@
 use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
-use crate::types::Question;
+use crate::types::{Difficulty, Question, QuizSettings, ReminderFrequency, ReminderStream, ReminderSubscriptions};
+
+mod callback_data;
+pub use callback_data::*;
 
 pub fn create_keyboard(
     question: &Question,
+    quiz_id: u32,
     selected_answer: Option<&str>,
     show_correct: bool,
     show_end_button: bool,
@@ -10,7 +14,8 @@ pub fn create_keyboard(
     let options = question.get_options();
     let mut keyboard: Vec<Vec<InlineKeyboardButton>> = options
         .iter()
-        .map(|option| {
+        .enumerate()
+        .map(|(index, option)| {
             let mut text = option.clone();
             if let Some(selected) = selected_answer {
                 if option == selected {
@@ -23,13 +28,127 @@ pub fn create_keyboard(
                     text = format!("✅ {}", option);
                 }
             }
-            vec![InlineKeyboardButton::callback(text, option.clone())]
+            let data = QuizCallbackData::answer(quiz_id, question.id, index as u8);
+            vec![InlineKeyboardButton::callback(text, data.encode())]
         })
         .collect();
 
     if show_end_button {
-        keyboard.push(vec![InlineKeyboardButton::callback("🛑 End Quiz".to_string(), "end_quiz".to_string())]);
+        let data = QuizCallbackData::end(quiz_id, question.id);
+        keyboard.push(vec![InlineKeyboardButton::callback("🛑 End Quiz".to_string(), data.encode())]);
     }
     
     InlineKeyboardMarkup::new(keyboard)
 }
+
+// One button per category, labelled with how many questions it has
+pub fn create_category_keyboard(categories: &[(String, usize)]) -> InlineKeyboardMarkup {
+    let keyboard: Vec<Vec<InlineKeyboardButton>> = categories
+        .iter()
+        .enumerate()
+        .map(|(index, (category, count))| {
+            let data = ThemeCallbackData::new(index);
+            vec![InlineKeyboardButton::callback(format!("{} ({})", category, count), data.encode())]
+        })
+        .collect();
+
+    InlineKeyboardMarkup::new(keyboard)
+}
+
+// Settings picker for /settings; the current choice in each row is ticked
+pub fn create_settings_keyboard(settings: &QuizSettings, categories: &[(String, usize)]) -> InlineKeyboardMarkup {
+    fn button(label: String, selected: bool, change: SettingsChange) -> InlineKeyboardButton {
+        let text = if selected { format!("✅ {}", label) } else { label };
+        InlineKeyboardButton::callback(text, SettingsCallbackData::new(change).encode())
+    }
+
+    let mut keyboard = Vec::new();
+
+    keyboard.push(
+        [5, 10, 15, 20]
+            .iter()
+            .map(|&length| button(length.to_string(), settings.length == length, SettingsChange::Length(length)))
+            .collect(),
+    );
+
+    let mut category_buttons = vec![button(
+        "Any category".to_string(),
+        settings.category.is_none(),
+        SettingsChange::Category(None),
+    )];
+    category_buttons.extend(categories.iter().enumerate().map(|(index, (category, _))| {
+        button(
+            category.clone(),
+            settings.category.as_ref() == Some(category),
+            SettingsChange::Category(Some(index)),
+        )
+    }));
+    keyboard.extend(category_buttons.chunks(2).map(|row| row.to_vec()));
+
+    let mut difficulty_row = vec![button(
+        "Any".to_string(),
+        !settings.adaptive && settings.difficulty.is_none(),
+        SettingsChange::Difficulty(None),
+    )];
+    difficulty_row.extend(Difficulty::ALL.iter().map(|&difficulty| {
+        button(
+            difficulty.to_string(),
+            !settings.adaptive && settings.difficulty == Some(difficulty),
+            SettingsChange::Difficulty(Some(difficulty)),
+        )
+    }));
+    keyboard.push(difficulty_row);
+    keyboard.push(vec![button("🎯 Adaptive".to_string(), settings.adaptive, SettingsChange::Adaptive)]);
+
+    let mut timer_row = vec![button(
+        "⏱ Off".to_string(),
+        settings.time_limit_secs.is_none(),
+        SettingsChange::TimeLimit(None),
+    )];
+    timer_row.extend([15, 30, 60].iter().map(|&secs| {
+        button(
+            format!("{}s", secs),
+            settings.time_limit_secs == Some(secs),
+            SettingsChange::TimeLimit(Some(secs)),
+        )
+    }));
+    keyboard.push(timer_row);
+
+    keyboard.push(vec![button("▶️ Start Quiz".to_string(), false, SettingsChange::Start)]);
+
+    InlineKeyboardMarkup::new(keyboard)
+}
+
+// Subscription picker for /preferences: an on/off button per stream, then its frequencies
+pub fn create_subscriptions_keyboard(subscriptions: &ReminderSubscriptions) -> InlineKeyboardMarkup {
+    fn button(label: String, selected: bool, change: SubscriptionChange) -> InlineKeyboardButton {
+        let text = if selected { format!("✅ {}", label) } else { label };
+        InlineKeyboardButton::callback(text, SubscriptionCallbackData::new(change).encode())
+    }
+
+    let mut keyboard = Vec::new();
+    for stream in ReminderStream::ALL {
+        let subscription = subscriptions.get(stream);
+        let toggle = if subscription.enabled { "🔔" } else { "🔕" };
+        keyboard.push(vec![InlineKeyboardButton::callback(
+            format!("{} {}", toggle, stream.label()),
+            SubscriptionCallbackData::new(SubscriptionChange::Toggle(stream)).encode(),
+        )]);
+        if subscription.enabled {
+            keyboard.push(
+                ReminderFrequency::ALL
+                    .iter()
+                    .map(|&frequency| {
+                        button(
+                            frequency.label().to_string(),
+                            subscription.frequency == frequency,
+                            SubscriptionChange::Frequency(stream, frequency),
+                        )
+                    })
+                    .collect(),
+            );
+        }
+    }
+
+    InlineKeyboardMarkup::new(keyboard)
+}
@@ -0,0 +1,191 @@
+use crate::category::match_category;
+use crate::types::{ScoreEvent, UserScore};
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+
+pub const LEADERBOARD_SIZE: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaderboardWindow {
+    Today,
+    Week,
+    Month,
+    All,
+}
+
+impl LeaderboardWindow {
+    // No argument means all time, like the leaderboard always showed
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "today" | "day" | "daily" => Some(LeaderboardWindow::Today),
+            "week" | "weekly" => Some(LeaderboardWindow::Week),
+            "month" | "monthly" => Some(LeaderboardWindow::Month),
+            "" | "all" | "alltime" | "all-time" => Some(LeaderboardWindow::All),
+            _ => None,
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            LeaderboardWindow::Today => "Today",
+            LeaderboardWindow::Week => "This Week",
+            LeaderboardWindow::Month => "This Month",
+            LeaderboardWindow::All => "All Time",
+        }
+    }
+
+    /// Start of the window containing `now`, with days starting at midnight in
+    /// `tz` and weeks on Monday. All time has no start.
+    pub fn start(self, now: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let today = now.with_timezone(&tz).date_naive();
+        let first_day = match self {
+            LeaderboardWindow::Today => today,
+            LeaderboardWindow::Week => today - Days::new(today.weekday().num_days_from_monday() as u64),
+            LeaderboardWindow::Month => today.with_day(1)?,
+            LeaderboardWindow::All => return None,
+        };
+        Some(local_midnight(first_day, tz))
+    }
+}
+
+fn local_midnight(day: NaiveDate, tz: Tz) -> DateTime<Utc> {
+    let midnight = day.and_time(NaiveTime::MIN);
+    // Where a DST change skips midnight, fall back to midnight UTC
+    tz.from_local_datetime(&midnight)
+        .earliest()
+        .map(|start| start.with_timezone(&Utc))
+        .unwrap_or_else(|| midnight.and_utc())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderboardQuery {
+    pub window: LeaderboardWindow,
+    // Rank everyone rather than just the group the command was sent in
+    pub global: bool,
+    pub category: Option<String>,
+}
+
+/// Parses `/leaderboard` arguments such as "week", "global month" or
+/// "hadith week". Words that aren't a window or "global" are matched against
+/// `categories`.
+pub fn parse_leaderboard_args(args: &str, categories: &[&str]) -> Option<LeaderboardQuery> {
+    let mut global = false;
+    let mut window = None;
+    let mut category_words = Vec::new();
+    for word in args.split_whitespace() {
+        if word.eq_ignore_ascii_case("global") {
+            global = true;
+        } else if let Some(parsed) = LeaderboardWindow::parse(word) {
+            if window.replace(parsed).is_some() {
+                return None;
+            }
+        } else {
+            category_words.push(word);
+        }
+    }
+
+    let category = if category_words.is_empty() {
+        None
+    } else {
+        Some(match_category(&category_words.join(" "), categories)?.to_string())
+    };
+
+    Some(LeaderboardQuery {
+        window: window.unwrap_or(LeaderboardWindow::All),
+        global,
+        category,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderboardEntry {
+    pub user_id: i64,
+    pub username: String,
+    pub points: u32,
+}
+
+/// Totals the points each user earned from `since` onwards, best first, counting
+/// only points earned in `chat_id` when given. Users with no points in the
+/// window are left out.
+pub fn rank_events<'a>(
+    events: impl IntoIterator<Item = &'a ScoreEvent>,
+    since: Option<DateTime<Utc>>,
+    chat_id: Option<i64>,
+) -> Vec<LeaderboardEntry> {
+    let mut totals: HashMap<i64, LeaderboardEntry> = HashMap::new();
+    let in_scope = |e: &&ScoreEvent| since.is_none_or(|since| e.at >= since) && chat_id.is_none_or(|c| e.chat_id == Some(c));
+    for event in events.into_iter().filter(in_scope) {
+        let entry = totals.entry(event.user_id).or_insert_with(|| LeaderboardEntry {
+            user_id: event.user_id,
+            username: String::new(),
+            points: 0,
+        });
+        // Events are in order, so this ends up as the latest name
+        entry.username = event.username.clone();
+        entry.points += event.points;
+    }
+    sorted(totals.into_values().collect())
+}
+
+// All-time standings come from the lifetime totals, which predate the event log
+pub fn rank_scores(scores: &HashMap<i64, UserScore>) -> Vec<LeaderboardEntry> {
+    sorted(
+        scores
+            .values()
+            .map(|s| LeaderboardEntry {
+                user_id: s.user_id,
+                username: s.username.clone(),
+                points: s.score,
+            })
+            .collect(),
+    )
+}
+
+// All-time standings within one category, from the points kept per category
+pub fn rank_category_scores(scores: &HashMap<i64, UserScore>, category: &str) -> Vec<LeaderboardEntry> {
+    sorted(
+        scores
+            .values()
+            .filter_map(|s| {
+                s.categories.get(category).map(|stats| LeaderboardEntry {
+                    user_id: s.user_id,
+                    username: s.username.clone(),
+                    points: stats.points,
+                })
+            })
+            .collect(),
+    )
+}
+
+fn sorted(mut entries: Vec<LeaderboardEntry>) -> Vec<LeaderboardEntry> {
+    entries.retain(|e| e.points > 0);
+    entries.sort_by(|a, b| b.points.cmp(&a.points).then_with(|| a.username.cmp(&b.username)));
+    entries
+}
+
+/// Formats the top of the standings, followed by the caller's own rank when
+/// they didn't make the top.
+pub fn format_leaderboard(title: &str, entries: &[LeaderboardEntry], caller_id: i64) -> String {
+    if entries.is_empty() {
+        return format!("🏆 Leaderboard ({}):\n\nNo points scored yet.", title);
+    }
+
+    let mut text = format!("🏆 Leaderboard ({}):\n\n", title);
+    let lines = entries
+        .iter()
+        .take(LEADERBOARD_SIZE)
+        .enumerate()
+        .map(|(i, entry)| format!("{}. {} - {} points", i + 1, entry.username, entry.points))
+        .collect::<Vec<_>>();
+    text.push_str(&lines.join("\n"));
+
+    match entries.iter().position(|e| e.user_id == caller_id) {
+        Some(rank) if rank >= LEADERBOARD_SIZE => {
+            text.push_str(&format!("\n...\n{}. You - {} points", rank + 1, entries[rank].points));
+        }
+        Some(_) => {}
+        None => text.push_str("\n\nYou haven't scored any points in this period yet."),
+    }
+    text
+}
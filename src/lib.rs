@@ -4,10 +4,24 @@ mod handlers;
 mod error;
 mod state;
 mod keyboard;
+mod deck;
+mod category;
+mod leaderboard;
+mod achievements;
+mod storage;
+mod scheduler;
+mod delivery;
 
 pub use types::*;
 pub use commands::*;
 pub use handlers::*;
 pub use error::*;
 pub use state::*;
-pub use keyboard::*;
\ No newline at end of file
+pub use keyboard::*;
+pub use deck::*;
+pub use category::*;
+pub use leaderboard::*;
+pub use achievements::*;
+pub use storage::*;
+pub use scheduler::*;
+pub use delivery::*;
@@ -4,10 +4,11 @@ use tokio::sync::Mutex;
 use std::collections::HashMap;
 use rand::SeedableRng;
 use rand::rngs::StdRng;
-use crate::types::UserScore;
-use crate::handlers::{command_handler, recursive_callback_handler, start_reminder_sender};
-use crate::state::BotState;
+use crate::storage::{JsonStorage, MemoryStorage, SqliteStorage, Storage};
+use crate::handlers::{command_handler, recursive_callback_handler, resume_question_timers, start_reminder_sender};
+use crate::state::{spawn_background_writer, BotState, DirtyFlags, TemplateSource, SAVE_INTERVAL};
 use crate::commands::Command;
+use crate::scheduler::{CatchUp, Scheduler, SystemClock};
 use axum::Router;
 use shuttle_runtime::SecretStore;
 
@@ -17,6 +18,13 @@ mod handlers;
 mod error;
 mod state;
 mod keyboard;
+mod deck;
+mod category;
+mod leaderboard;
+mod achievements;
+mod storage;
+mod scheduler;
+mod delivery;
 
 #[shuttle_runtime::main]
 async fn axum(
@@ -37,18 +45,68 @@ async fn axum(
         .expect("Failed to load questions");
     log::info!("Loaded {} questions", questions.len());
 
-    let (reminder_templates, reminder_templates_act) = state::load_reminder_templates()
-        .await
-        .expect("Failed to load reminder templates");
-    
+    // The bot still works without achievements, so a bad file isn't fatal
+    let achievements = state::load_achievements().unwrap_or_else(|e| {
+        log::error!("Failed to load achievements: {}. Starting without them.", e);
+        Vec::new()
+    });
+    log::info!("Loaded {} achievements", achievements.len());
+
+    // Each template set comes from its sheet, cached locally for when it can't be reached
+    let reminder_source = TemplateSource::reminders()
+        .with_overrides(secret_store.get("REMINDERS_PATH"), secret_store.get("REMINDERS_URL"));
+    let reminder_act_source = TemplateSource::reminder_acts()
+        .with_overrides(secret_store.get("REMINDER_ACTS_PATH"), secret_store.get("REMINDER_ACTS_URL"));
+    let (reminder_templates, reminder_templates_act) =
+        state::load_reminder_templates(&reminder_source, &reminder_act_source).await;
+
     log::info!("Loaded {} reminder templates", reminder_templates.len());
     log::info!("Loaded {} reminder templates Act", reminder_templates_act.len());
 
-    let user_scores = UserScore::load_scores()
+    // JSON files in the working directory unless configured otherwise
+    let storage: Arc<dyn Storage> = match secret_store.get("STORAGE_BACKEND").as_deref() {
+        Some("memory") => {
+            log::warn!("Using in-memory storage, nothing will be kept across restarts");
+            Arc::new(MemoryStorage::default())
+        }
+        Some("sqlite") => {
+            let path = secret_store.get("SQLITE_PATH").unwrap_or_else(|| "trivia.db".to_string());
+            let sqlite = SqliteStorage::open(&path).expect("Failed to open SQLite database");
+            // Bring the existing JSON files across until an import has gone through
+            if sqlite.needs_import().await.expect("Failed to read the SQLite database") {
+                let summary = sqlite
+                    .import_from(&JsonStorage::new("."))
+                    .await
+                    .expect("Failed to import JSON data into SQLite");
+                log::info!("Imported JSON data into {}: {:?}", path, summary);
+            }
+            Arc::new(sqlite)
+        }
+        _ => Arc::new(JsonStorage::new(".")),
+    };
+
+    let user_scores = storage.load_scores().await
         .expect("Failed to load user scores");
     log::info!("Loaded scores for {} users", user_scores.len());
 
-    let user_preferences = match BotState::initialize_preferences().await {
+    let score_events = storage.load_score_events().await
+        .expect("Failed to load score events");
+    log::info!("Loaded {} score events", score_events.len());
+
+    // Leaderboard days, weeks and months follow this timezone, UTC unless configured
+    let leaderboard_timezone = match secret_store.get("LEADERBOARD_TIMEZONE") {
+        Some(name) => name.parse().unwrap_or_else(|e| {
+            log::error!("Invalid LEADERBOARD_TIMEZONE {:?}: {}. Using UTC.", name, e);
+            chrono_tz::UTC
+        }),
+        None => chrono_tz::UTC,
+    };
+
+    let question_history = storage.load_history().await
+        .expect("Failed to load question history");
+    log::info!("Loaded question history for {} users", question_history.len());
+
+    let user_preferences = match storage.load_preferences().await {
         Ok(prefs) => {
             log::info!("Successfully initialized preferences for {} users", prefs.len());
             prefs
@@ -59,6 +117,10 @@ async fn axum(
         }
     };
 
+    let quiz_settings = storage.load_quiz_settings().await
+        .expect("Failed to load quiz settings");
+    log::info!("Loaded quiz settings for {} users", quiz_settings.len());
+
     // Initialize bot state
     let state = Arc::new(BotState {
         questions,
@@ -68,15 +130,45 @@ async fn axum(
         reminder_templates,
         reminder_templates_act,
         user_preferences: Mutex::new(user_preferences),
+        question_history: Mutex::new(question_history),
+        score_events: Mutex::new(score_events),
+        quiz_settings: Mutex::new(quiz_settings),
+        leaderboard_timezone,
+        achievements,
+        storage,
+        dirty: DirtyFlags::default(),
     });
 
+    // Pick up quizzes that were running when the bot last stopped
+    match state.storage.load_active_quizzes().await {
+        Ok(saved) => {
+            let restored = state.restore_active_quizzes(saved, chrono::Utc::now()).await;
+            log::info!("Restored {} quizzes in progress", restored);
+            resume_question_timers(bot.clone(), state.clone()).await;
+        }
+        Err(e) => log::error!("Failed to load quizzes in progress: {}", e),
+    }
+
+    // Scores and preferences are saved in the background rather than on every change
+    spawn_background_writer(state.clone(), SAVE_INTERVAL);
+
+    // Reminders missed while the bot was down are skipped unless configured otherwise
+    let catch_up = match secret_store.get("REMINDER_CATCH_UP") {
+        Some(policy) => CatchUp::parse(&policy).unwrap_or_else(|| {
+            log::error!("Invalid REMINDER_CATCH_UP {:?}, expected skip, once or all. Using skip.", policy);
+            CatchUp::Skip
+        }),
+        None => CatchUp::Skip,
+    };
+    let scheduler = Scheduler::new(Arc::new(SystemClock), catch_up);
+
     // Clone bot and state for reminder service
     let reminder_bot = bot.clone();
     let reminder_state = state.clone();
 
     // Spawn reminder service
     tokio::spawn(async move {
-        start_reminder_sender(reminder_bot, reminder_state).await;
+        start_reminder_sender(reminder_bot, reminder_state, scheduler).await;
     });
 
     let handler = dptree::entry()
@@ -94,13 +186,44 @@ async fn axum(
 
     // Create and run the dispatcher in the background
     let mut dispatcher = Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![state])
-        .enable_ctrlc_handler()
+        .dependencies(dptree::deps![state.clone()])
         .build();
+    let shutdown_token = dispatcher.shutdown_token();
 
     tokio::spawn(async move {
         dispatcher.dispatch().await;
     });
 
+    // Let in-flight updates finish, then save whatever the background writer
+    // hasn't got to yet
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        log::info!("Shutting down, saving state...");
+        if let Ok(stopped) = shutdown_token.shutdown() {
+            stopped.await;
+        }
+        if let Err(e) = state.flush().await {
+            log::error!("Failed to save state on shutdown: {}", e);
+        }
+        std::process::exit(0);
+    });
+
     Ok(Router::new().into())
 }
+
+// Shuttle stops a deployment with SIGTERM, Ctrl-C sends SIGINT
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -0,0 +1,249 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+
+// A run this late still counts as on time under `CatchUp::Skip`
+pub const CATCH_UP_GRACE: Duration = Duration::minutes(15);
+
+// The most runs `CatchUp::All` sends after a long downtime
+pub const MAX_CATCH_UP_RUNS: usize = 10;
+
+// How far ahead `CronRule::next_after` looks, enough for a rule that only
+// matches on 29 February
+const MAX_SEARCH_DAYS: i64 = 366 * 8;
+
+/// Where the scheduler gets the time from, so tests can set it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// What to do with runs that were missed while the bot was down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CatchUp {
+    // Drop them, unless the latest one is within `CATCH_UP_GRACE`
+    #[default]
+    Skip,
+    // Make up for all of them with a single run
+    Once,
+    // Make up every one of them, up to `MAX_CATCH_UP_RUNS`
+    All,
+}
+
+impl CatchUp {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "skip" => Some(CatchUp::Skip),
+            "once" => Some(CatchUp::Once),
+            "all" => Some(CatchUp::All),
+            _ => None,
+        }
+    }
+}
+
+/// The runs of a job due now and when it runs next.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DueRuns {
+    pub runs: Vec<DateTime<Utc>>,
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+pub struct Scheduler {
+    clock: Arc<dyn Clock>,
+    catch_up: CatchUp,
+}
+
+impl Scheduler {
+    pub fn new(clock: Arc<dyn Clock>, catch_up: CatchUp) -> Self {
+        Self { clock, catch_up }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Works out which runs of a job are due, given its saved `next_run` and
+    /// `next_after` giving the run that follows a time. Missed runs are
+    /// dropped or kept according to the catch-up policy.
+    pub fn due_runs(
+        &self,
+        next_run: DateTime<Utc>,
+        next_after: impl Fn(DateTime<Utc>) -> Option<DateTime<Utc>>,
+    ) -> DueRuns {
+        let now = self.now();
+        // Only the latest runs can ever be sent, so only those are kept
+        let mut missed = VecDeque::with_capacity(MAX_CATCH_UP_RUNS + 1);
+        let mut upcoming = Some(next_run);
+        while let Some(run) = upcoming.filter(|run| *run <= now) {
+            missed.push_back(run);
+            if missed.len() > MAX_CATCH_UP_RUNS {
+                missed.pop_front();
+            }
+            upcoming = next_after(run);
+        }
+
+        let runs = match self.catch_up {
+            CatchUp::Skip => missed.back().filter(|run| now - **run <= CATCH_UP_GRACE).copied().into_iter().collect(),
+            CatchUp::Once => missed.back().copied().into_iter().collect(),
+            CatchUp::All => missed.into(),
+        };
+        DueRuns { runs, next_run: upcoming }
+    }
+}
+
+/// A cron-style rule, `minute hour day-of-month month day-of-week`, read in
+/// the user's timezone. Fields take `*`, numbers, lists, ranges and steps,
+/// e.g. `0 6,18 * * 1-5` or `*/30 9-17 * * *`. Sunday is 0 or 7.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronRule {
+    expr: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    // Cron matches either day field when both are restricted
+    any_day_of_month: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let invalid = || format!("Invalid cron field {:?}", field);
+    let mut allowed = vec![false; max as usize + 1];
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse().map_err(|_| invalid())?,
+                    end.parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    // `5/15` means from 5 to the end in steps of 15
+                    (value, if step > 1 { max } else { value })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+impl CronRule {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, weekday] = fields.as_slice() else {
+            return Err("A cron rule has five fields: minute hour day-of-month month day-of-week".to_string());
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+
+        Ok(Self {
+            expr: fields.join(" "),
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days_of_month: parse_field(day_of_month, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            any_day_of_month: *day_of_month == "*",
+            any_weekday: *weekday == "*",
+        })
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !self.months[date.month() as usize] {
+            return false;
+        }
+        let day_of_month = self.days_of_month[date.day() as usize];
+        let weekday = self.weekdays[date.weekday().num_days_from_sunday() as usize];
+        match (self.any_day_of_month, self.any_weekday) {
+            (false, false) => day_of_month || weekday,
+            _ => day_of_month && weekday,
+        }
+    }
+
+    // The (hour, minute) pairs that match on any matching day, earliest first
+    fn times_of_day(&self) -> Vec<(u32, u32)> {
+        (0..24)
+            .filter(|h| self.hours[*h as usize])
+            .flat_map(|h| (0..60).filter(|m| self.minutes[*m as usize]).map(move |m| (h, m)))
+            .collect()
+    }
+
+    /// The shortest gap there can be between two runs, counting from the last
+    /// run of one day to the first of the next as if both days matched.
+    pub fn min_interval(&self) -> Duration {
+        let minutes: Vec<i64> = self.times_of_day().iter().map(|(h, m)| i64::from(h * 60 + m)).collect();
+        let overnight = match (minutes.first(), minutes.last()) {
+            (Some(first), Some(last)) => first + 24 * 60 - last,
+            _ => 24 * 60,
+        };
+        let shortest = minutes.windows(2).map(|pair| pair[1] - pair[0]).fold(overnight, i64::min);
+        Duration::minutes(shortest)
+    }
+
+    /// The first time strictly after `after` that matches, in `timezone`.
+    /// Local times skipped by a DST change don't match.
+    pub fn next_after(&self, after: DateTime<Utc>, timezone: Tz) -> Option<DateTime<Utc>> {
+        let start = after.with_timezone(&timezone).date_naive();
+        let times = self.times_of_day();
+
+        (0..MAX_SEARCH_DAYS)
+            .filter_map(|days| start.checked_add_signed(Duration::days(days)))
+            .filter(|date| self.matches_date(*date))
+            .find_map(|date| {
+                times
+                    .iter()
+                    .filter_map(|(h, m)| date.and_hms_opt(*h, *m, 0))
+                    .filter_map(|local| timezone.from_local_datetime(&local).earliest())
+                    .map(|at| at.with_timezone(&Utc))
+                    .find(|at| *at > after)
+            })
+    }
+}
+
+impl fmt::Display for CronRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expr)
+    }
+}
+
+impl TryFrom<String> for CronRule {
+    type Error = String;
+
+    fn try_from(expr: String) -> Result<Self, Self::Error> {
+        CronRule::parse(&expr)
+    }
+}
+
+impl From<CronRule> for String {
+    fn from(rule: CronRule) -> Self {
+        rule.expr
+    }
+}
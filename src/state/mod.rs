@@ -1,17 +1,25 @@
-use crate::types::{Question, ActiveQuestion, UserScore};
-use std::collections::HashMap;
+use crate::types::{Achievement, CompletedQuiz, Question, ActiveQuestion, Difficulty, QuestionHistory, QuizSettings, ScoreEvent, UserScore};
+use crate::deck::{adjust_difficulty, deal_questions};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::path::Path;
 use tokio::sync::Mutex;
 use rand::rngs::StdRng;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use rand::Rng;
+use chrono_tz::Tz;
+use std::sync::Arc;
+use crate::storage::Storage;
+
+mod persistence;
+mod reminders;
+pub use persistence::*;
+pub use reminders::*;
+
 use crate::error::ScoreError;
 use crate::types::{ReminderTemplate, ReminderTemplateAct, UserReminderPreferences};
 use tokio::time::timeout;
 use std::time::Duration;
-use reqwest;
 pub struct BotState {
     pub questions: Vec<Question>,
     pub active_questions: Mutex<HashMap<i64, ActiveQuestion>>,
@@ -20,48 +28,139 @@ pub struct BotState {
     pub reminder_templates: Vec<ReminderTemplate>,
     pub reminder_templates_act: Vec<ReminderTemplateAct>,
     pub user_preferences: Mutex<HashMap<i64, UserReminderPreferences>>,
+    pub question_history: Mutex<HashMap<i64, QuestionHistory>>,
+    pub score_events: Mutex<Vec<ScoreEvent>>,
+    pub quiz_settings: Mutex<HashMap<i64, QuizSettings>>,
+    // Where days, weeks and months start for the leaderboards
+    pub leaderboard_timezone: Tz,
+    pub achievements: Vec<Achievement>,
+    pub storage: Arc<dyn Storage>,
+    pub dirty: DirtyFlags,
 }
 
 impl BotState {
+    pub fn question(&self, id: u32) -> Option<&Question> {
+        self.questions.iter().find(|q| q.id == id)
+    }
+
+    // Removes the chat's active question, but only if it is still the given one
+    pub async fn take_active_question(&self, chat_id: i64, quiz_id: u32, question_id: u32) -> Option<ActiveQuestion> {
+        let mut active_questions = self.active_questions.lock().await;
+        match active_questions.get(&chat_id) {
+            Some(active) if active.quiz_id == quiz_id && active.question_id == question_id => {
+                self.mark_quizzes_dirty();
+                active_questions.remove(&chat_id)
+            }
+            _ => None,
+        }
+    }
+
+    pub async fn new_quiz_id(&self) -> u32 {
+        self.rng.lock().await.gen()
+    }
+
+    // Deals questions the user hasn't seen yet, optionally limited to one category
+    // and difficulty, and records them in the user's history
+    pub async fn deal_questions(
+        &self,
+        user_id: i64,
+        category: Option<&str>,
+        difficulty: Option<Difficulty>,
+        count: usize,
+    ) -> Vec<u32> {
+        let pool: Vec<u32> = self
+            .questions
+            .iter()
+            .filter(|q| category.is_none_or(|c| q.category.eq_ignore_ascii_case(c)))
+            .filter(|q| difficulty.is_none_or(|d| q.difficulty() == d))
+            .map(|q| q.id)
+            .collect();
+
+        let dealt = {
+            let mut history = self.question_history.lock().await;
+            let history = history.entry(user_id).or_insert_with(|| QuestionHistory::new(user_id));
+            let mut rng = self.rng.lock().await;
+            deal_questions(&pool, &mut history.seen_questions, count, &mut *rng)
+        };
+
+        self.mark_history_dirty();
+        dealt
+    }
+
+    // Settles the user's adaptive level from their recent answers and returns it
+    pub async fn adaptive_difficulty(&self, user_id: i64) -> Difficulty {
+        let level = {
+            let mut history = self.question_history.lock().await;
+            let history = history.entry(user_id).or_insert_with(|| QuestionHistory::new(user_id));
+            let level = adjust_difficulty(history.adaptive_level, &history.recent_answers);
+            if level != history.adaptive_level {
+                // Start a fresh window so one good run doesn't jump straight to the top
+                history.adaptive_level = level;
+                history.recent_answers.clear();
+            }
+            level
+        };
+
+        self.mark_history_dirty();
+        level
+    }
+
+    pub async fn record_answer(&self, user_id: i64, is_correct: bool) {
+        self.question_history
+            .lock()
+            .await
+            .entry(user_id)
+            .or_insert_with(|| QuestionHistory::new(user_id))
+            .record_answer(is_correct);
+
+        self.mark_history_dirty();
+    }
+
+    pub async fn quiz_settings(&self, user_id: i64) -> QuizSettings {
+        self.quiz_settings.lock().await.get(&user_id).cloned().unwrap_or_default()
+    }
+
+    pub async fn set_quiz_settings(&self, user_id: i64, settings: QuizSettings) {
+        self.quiz_settings.lock().await.insert(user_id, settings);
+        self.mark_settings_dirty();
+    }
+
+    // Keeps the event in memory for the leaderboards and appends it to the log.
+    // The lock is released before the write so leaderboards don't wait on the disk
+    pub async fn record_score_event(&self, event: ScoreEvent) {
+        self.score_events.lock().await.push(event.clone());
+        if let Err(e) = self.storage.append_score_event(&event).await {
+            log::error!("Failed to append score event: {}", e);
+        }
+    }
+
+    pub async fn record_completed_quiz(&self, quiz: CompletedQuiz) {
+        if let Err(e) = self.storage.append_session(&quiz).await {
+            log::error!("Failed to record quiz session: {}", e);
+        }
+    }
+
+    pub async fn save_history(&self) -> Result<(), ScoreError> {
+        let history = self.question_history.lock().await;
+        self.storage.save_history(&history).await
+    }
+
     pub async fn save_scores(&self) -> Result<(), ScoreError> {
         let scores = self.user_scores.lock().await;
-        UserScore::save_scores_async(&scores).await
-    }
-
-    pub async fn initialize_preferences() -> Result<HashMap<i64, UserReminderPreferences>, ScoreError> {
-        let preferences_path = Path::new("user_preferences.json");
-        
-        if !preferences_path.exists() {
-            log::info!("Creating new user_preferences.json file");
-            let empty_prefs: HashMap<i64, UserReminderPreferences> = HashMap::new();
-            let json = serde_json::to_string_pretty(&empty_prefs)?;
-            let mut file = File::create(preferences_path).await?;
-            file.write_all(json.as_bytes()).await?;
-            Ok(empty_prefs)
-        } else {
-            log::info!("Loading existing user_preferences.json");
-            let json = fs::read_to_string(preferences_path)?;
-            let preferences = serde_json::from_str(&json)?;
-            Ok(preferences)
-        }
+        self.storage.save_scores(&scores).await
     }
 
     pub async fn save_preferences(&self) -> Result<(), ScoreError> {
         let preferences = self.user_preferences.lock().await;
-        let json = serde_json::to_string_pretty(&*preferences)?;
-        
-        // Create a temporary file first
-        let temp_path = Path::new("user_preferences.tmp.json");
-        let mut temp_file = File::create(temp_path).await?;
-        temp_file.write_all(json.as_bytes()).await?;
-        
-        // Rename temporary file to actual file
-        tokio::fs::rename(temp_path, "user_preferences.json").await?;
-        
-        Ok(())
-    }
-
-    pub async fn acquire_preferences_lock(&self) -> Result<tokio::sync::MutexGuard<HashMap<i64, UserReminderPreferences>>, Box<dyn Error + Send + Sync>> {
+        self.storage.save_preferences(&preferences).await
+    }
+
+    pub async fn save_quiz_settings(&self) -> Result<(), ScoreError> {
+        let settings = self.quiz_settings.lock().await;
+        self.storage.save_quiz_settings(&settings).await
+    }
+
+    pub async fn acquire_preferences_lock(&self) -> Result<tokio::sync::MutexGuard<'_, HashMap<i64, UserReminderPreferences>>, Box<dyn Error + Send + Sync>> {
         match timeout(Duration::from_secs(5), self.user_preferences.lock()).await {
             Ok(guard) => Ok(guard),
             Err(_) => {
@@ -72,102 +171,44 @@ impl BotState {
     }
 }
 
-impl UserScore {
-    const SCORES_FILE: &'static str = "user_scores.json";
-    
-    pub async fn save_scores_async(scores: &HashMap<i64, UserScore>) -> Result<(), ScoreError> {
-        let json = serde_json::to_string_pretty(scores)?;
-        let mut file = File::create(Self::SCORES_FILE).await?;
-        file.write_all(json.as_bytes()).await?;
-        Ok(())
-    }
-    
-    pub fn load_scores() -> Result<HashMap<i64, UserScore>, ScoreError> {
-        if Path::new(Self::SCORES_FILE).exists() {
-            let json = fs::read_to_string(Self::SCORES_FILE)?;
-            let scores = serde_json::from_str(&json)?;
-            Ok(scores)
-        } else {
-            Ok(HashMap::new())
-        }
-    }
+pub fn load_questions() -> Result<Vec<Question>, Box<dyn Error>> {
+    load_questions_from("questions.csv")
 }
 
-pub fn load_questions() -> Result<Vec<Question>, Box<dyn Error>> {
+// Question ids are referenced by callback data and active quizzes, so every row
+// must have one and it must be unique
+pub fn load_questions_from<P: AsRef<Path>>(path: P) -> Result<Vec<Question>, Box<dyn Error>> {
     let mut questions = Vec::new();
-    let mut rdr = csv::Reader::from_path("questions.csv").expect("question csv failed");
-    
+    let mut seen_ids = HashSet::new();
+    let mut rdr = csv::Reader::from_path(path)?;
+
     for result in rdr.deserialize() {
-        let question: Question = result?;
+        let mut question: Question = result.map_err(|e| format!("Invalid question row: {}", e))?;
+        if question.points == 0 {
+            question.points = question.difficulty().points();
+        }
+        if !seen_ids.insert(question.id) {
+            return Err(format!("Duplicate question id {}", question.id).into());
+        }
         questions.push(question);
     }
     Ok(questions)
 }
 
-pub async fn load_reminder_templates() -> Result<(Vec<ReminderTemplate>, Vec<ReminderTemplateAct>), Box<dyn Error>> {
-    let mut templates = Vec::new();
-    let mut templates2 = Vec::new();
-
-    // Make sure to use the correct export URL format
-    let url = "https://docs.google.com/spreadsheets/d/e/2PACX-1vRjbtw5iUNyWqrI1sFmAaO4KOxwLuwa_TgJub4b74Uv5uQYYSPl69BIoFBPLQbPhZ5oKn2y2cJrHdIL/pub?gid=0&single=true&output=csv";
-    let url2 = "https://docs.google.com/spreadsheets/d/e/2PACX-1vRjbtw5iUNyWqrI1sFmAaO4KOxwLuwa_TgJub4b74Uv5uQYYSPl69BIoFBPLQbPhZ5oKn2y2cJrHdIL/pub?gid=863587672&single=true&output=csv";
-    
-    let response = reqwest::get(url).await?;
-    let content = response.text().await?;
-
-    let response2 = reqwest::get(url2).await?;
-    let content2 = response2.text().await?;
-    
-    // Verify we got CSV and not HTML
-    if content.starts_with("<!DOCTYPE html>") {
-        return Err("Received HTML instead of CSV Sheet 1. Check if the sheet is properly published and accessible.".into());
-    }
-    
-    if content2.starts_with("<!DOCTYPE html>") {
-        return Err("Received HTML instead of CSV Sheet 2. Check if the sheet is properly published and accessible.".into());
-    }
-
-    let mut rdr = csv::ReaderBuilder::new()
-        .flexible(true)
-        .trim(csv::Trim::All)
-        .from_reader(content.as_bytes());
-
-        let mut rdr2 = csv::ReaderBuilder::new()
-        .flexible(true)
-        .trim(csv::Trim::All)
-        .from_reader(content2.as_bytes());
-
-    // Print headers to verify structure
-    println!("Headers: {:?}", rdr.headers()?);
-    println!("Headers: {:?}", rdr2.headers()?);
-    
-    for (index, result) in rdr.deserialize().enumerate() {
-        match result {
-            Ok(template) => templates.push(template),
-            Err(e) => {
-                println!("Error at row {}: {}", index + 1, e);
-                continue;
-            }
-        }
-    }
+pub fn load_achievements() -> Result<Vec<Achievement>, Box<dyn Error>> {
+    load_achievements_from("achievements.json")
+}
 
-    for (index, result) in rdr2.deserialize().enumerate() {
-        match result {
-            Ok(template) => templates2.push(template),
-            Err(e) => {
-                println!("Error at row {}: {}", index + 1, e);
-                continue;
-            }
-        }
-    }
-    
-    if templates.is_empty() {
-        return Err("No valid templates found".into());
-    }
+// Achievement ids are stored with each user, so they must be unique
+pub fn load_achievements_from<P: AsRef<Path>>(path: P) -> Result<Vec<Achievement>, Box<dyn Error>> {
+    let json = fs::read_to_string(path)?;
+    let achievements: Vec<Achievement> = serde_json::from_str(&json).map_err(|e| format!("Invalid achievements: {}", e))?;
 
-    if templates2.is_empty() {
-        return Err("No valid templates found".into());
+    let mut seen_ids = HashSet::new();
+    for achievement in &achievements {
+        if !seen_ids.insert(achievement.id.as_str()) {
+            return Err(format!("Duplicate achievement id {:?}", achievement.id).into());
+        }
     }
-    
-    Ok((templates, templates2))
-}
\ No newline at end of file
+    Ok(achievements)
+}
@@ -0,0 +1,141 @@
+use super::BotState;
+use crate::error::ScoreError;
+use crate::types::{ActiveQuestion, SavedQuiz};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+// How often the background writer saves whatever has changed
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Quizzes left unanswered for longer than this aren't brought back after a restart
+pub const MAX_RESTORED_QUIZ_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What has changed in memory since it was last saved.
+#[derive(Debug, Default)]
+pub struct DirtyFlags {
+    scores: AtomicBool,
+    preferences: AtomicBool,
+    history: AtomicBool,
+    settings: AtomicBool,
+    quizzes: AtomicBool,
+}
+
+impl BotState {
+    pub fn mark_scores_dirty(&self) {
+        self.dirty.scores.store(true, Ordering::Release);
+    }
+
+    pub fn mark_preferences_dirty(&self) {
+        self.dirty.preferences.store(true, Ordering::Release);
+    }
+
+    pub fn mark_history_dirty(&self) {
+        self.dirty.history.store(true, Ordering::Release);
+    }
+
+    pub fn mark_settings_dirty(&self) {
+        self.dirty.settings.store(true, Ordering::Release);
+    }
+
+    // Call after any change to `active_questions`
+    pub fn mark_quizzes_dirty(&self) {
+        self.dirty.quizzes.store(true, Ordering::Release);
+    }
+
+    /// Saves everything marked dirty. Anything that fails to save stays dirty
+    /// so the next flush tries again; the first error is returned.
+    pub async fn flush(&self) -> Result<(), ScoreError> {
+        let mut result = Ok(());
+
+        if self.dirty.scores.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.save_scores().await {
+                self.mark_scores_dirty();
+                result = result.and(Err(e));
+            }
+        }
+        if self.dirty.preferences.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.save_preferences().await {
+                self.mark_preferences_dirty();
+                result = result.and(Err(e));
+            }
+        }
+        if self.dirty.history.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.save_history().await {
+                self.mark_history_dirty();
+                result = result.and(Err(e));
+            }
+        }
+        if self.dirty.settings.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.save_quiz_settings().await {
+                self.mark_settings_dirty();
+                result = result.and(Err(e));
+            }
+        }
+        if self.dirty.quizzes.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.save_active_quizzes().await {
+                self.mark_quizzes_dirty();
+                result = result.and(Err(e));
+            }
+        }
+
+        result
+    }
+}
+
+impl BotState {
+    pub async fn save_active_quizzes(&self) -> Result<(), ScoreError> {
+        let now = Utc::now();
+        let saved: HashMap<i64, SavedQuiz> = self
+            .active_questions
+            .lock()
+            .await
+            .iter()
+            .map(|(chat_id, active)| (*chat_id, active.to_saved(now)))
+            .collect();
+        self.storage.save_active_quizzes(&saved).await
+    }
+
+    /// Puts quizzes saved before a restart back in play and returns how many
+    /// were restored. Quizzes that are too old, or that ask questions no longer in
+    /// the question bank, are dropped; their buttons get the expired reply.
+    pub async fn restore_active_quizzes(&self, saved: HashMap<i64, SavedQuiz>, now: DateTime<Utc>) -> usize {
+        let mut active_questions = self.active_questions.lock().await;
+        let mut restored = 0;
+
+        for (chat_id, quiz) in saved {
+            let too_old = now - quiz.asked_at > chrono::Duration::from_std(MAX_RESTORED_QUIZ_AGE).unwrap_or_default();
+            let questions_missing = std::iter::once(&quiz.question_id)
+                .chain(&quiz.remaining_questions)
+                .any(|id| self.question(*id).is_none());
+            if too_old || questions_missing {
+                log::info!("Dropping saved quiz in chat {}", chat_id);
+                continue;
+            }
+            active_questions.insert(chat_id, ActiveQuestion::from_saved(quiz, now));
+            restored += 1;
+        }
+
+        // Dropped quizzes shouldn't be saved again
+        self.mark_quizzes_dirty();
+        restored
+    }
+}
+
+/// Saves changed state every `every`, so a burst of answers costs one write
+/// instead of one per answer.
+pub fn spawn_background_writer(state: Arc<BotState>, every: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = state.flush().await {
+                log::error!("Failed to save state: {}", e);
+            }
+        }
+    })
+}
@@ -0,0 +1,211 @@
+use crate::storage::replace_file;
+use crate::types::{ReminderTemplate, ReminderTemplateAct};
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const REMINDERS_URL: &str = "https://docs.google.com/spreadsheets/d/e/2PACX-1vRjbtw5iUNyWqrI1sFmAaO4KOxwLuwa_TgJub4b74Uv5uQYYSPl69BIoFBPLQbPhZ5oKn2y2cJrHdIL/pub?gid=0&single=true&output=csv";
+const REMINDER_ACTS_URL: &str = "https://docs.google.com/spreadsheets/d/e/2PACX-1vRjbtw5iUNyWqrI1sFmAaO4KOxwLuwa_TgJub4b74Uv5uQYYSPl69BIoFBPLQbPhZ5oKn2y2cJrHdIL/pub?gid=863587672&single=true&output=csv";
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Where one set of reminder templates comes from: the sheet at `url`, and
+/// the copy bundled at `path`. A fetched sheet is cached next to the bundled
+/// file (`reminders.csv` is cached as `reminders.cache.csv`), and the cache
+/// is preferred to the bundled copy whenever the sheet can't be reached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateSource {
+    pub name: &'static str,
+    pub path: Option<PathBuf>,
+    pub url: Option<String>,
+}
+
+impl TemplateSource {
+    pub fn reminders() -> Self {
+        Self {
+            name: "reminders",
+            path: Some(PathBuf::from("reminders.csv")),
+            url: Some(REMINDERS_URL.to_string()),
+        }
+    }
+
+    pub fn reminder_acts() -> Self {
+        Self {
+            name: "reminder acts",
+            path: Some(PathBuf::from("reminder_acts.csv")),
+            url: Some(REMINDER_ACTS_URL.to_string()),
+        }
+    }
+
+    // Unset keeps the default, an empty value turns that side off
+    pub fn with_overrides(mut self, path: Option<String>, url: Option<String>) -> Self {
+        if let Some(path) = path {
+            self.path = (!path.trim().is_empty()).then(|| PathBuf::from(path.trim()));
+        }
+        if let Some(url) = url {
+            self.url = (!url.trim().is_empty()).then(|| url.trim().to_string());
+        }
+        self
+    }
+
+    // Kept apart from the bundled file so a fetch never overwrites it
+    pub fn cache_path(&self) -> Option<PathBuf> {
+        self.path.as_ref().map(|path| path.with_extension("cache.csv"))
+    }
+}
+
+/// A template row that was skipped, with its line in the CSV file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateRowError {
+    pub line: u64,
+    pub message: String,
+}
+
+impl fmt::Display for TemplateRowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub trait TemplateRow: DeserializeOwned {
+    // The first required column left blank, if any
+    fn missing_field(&self) -> Option<&'static str>;
+}
+
+impl TemplateRow for ReminderTemplate {
+    fn missing_field(&self) -> Option<&'static str> {
+        if self.message.is_empty() {
+            Some("message")
+        } else if self.arabic.is_empty() {
+            Some("arabic")
+        } else if self.translation.is_empty() {
+            Some("translation")
+        } else {
+            None
+        }
+    }
+}
+
+impl TemplateRow for ReminderTemplateAct {
+    fn missing_field(&self) -> Option<&'static str> {
+        if self.message.is_empty() {
+            Some("message")
+        } else if self.act.is_empty() {
+            Some("act")
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses a template CSV, keeping the valid rows and reporting the rest by line.
+/// Fails only when the file as a whole isn't a template CSV.
+pub fn parse_templates<T: TemplateRow>(content: &str) -> Result<(Vec<T>, Vec<TemplateRowError>), Box<dyn Error + Send + Sync>> {
+    // An unpublished sheet answers with its HTML sign-in page
+    if content.trim_start().starts_with('<') {
+        return Err("Received HTML instead of CSV. Check that the sheet is published.".into());
+    }
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(content.as_bytes());
+    let headers = rdr.headers()?.clone();
+
+    let mut templates = Vec::new();
+    let mut errors = Vec::new();
+    for result in rdr.records() {
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                errors.push(TemplateRowError { line, message: e.to_string() });
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        match record.deserialize::<T>(Some(&headers)) {
+            Ok(template) => match template.missing_field() {
+                Some(field) => errors.push(TemplateRowError { line, message: format!("missing {}", field) }),
+                None => templates.push(template),
+            },
+            Err(e) => errors.push(TemplateRowError { line, message: e.to_string() }),
+        }
+    }
+    Ok((templates, errors))
+}
+
+async fn fetch(url: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.text().await?)
+}
+
+fn parse_logged<T: TemplateRow>(source: &TemplateSource, origin: &str, content: &str) -> Option<Vec<T>> {
+    match parse_templates::<T>(content) {
+        Ok((templates, errors)) => {
+            for error in &errors {
+                log::warn!("Skipping {} row from {}, {}", source.name, origin, error);
+            }
+            if templates.is_empty() {
+                log::error!("No valid {} in {}", source.name, origin);
+                return None;
+            }
+            Some(templates)
+        }
+        Err(e) => {
+            log::error!("Failed to read {} from {}: {}", source.name, origin, e);
+            None
+        }
+    }
+}
+
+/// Loads the templates from `source`: the remote sheet first, refreshing the
+/// cache, then the cache, then the bundled file. Returns no templates if none
+/// of them works.
+pub async fn load_template_source<T: TemplateRow>(source: &TemplateSource) -> Vec<T> {
+    let cache = source.cache_path();
+
+    if let Some(url) = &source.url {
+        match fetch(url).await {
+            Ok(content) => {
+                if let Some(templates) = parse_logged(source, url, &content) {
+                    if let Some(cache) = &cache {
+                        // Replaced atomically, so a crash can't leave half a file
+                        if let Err(e) = replace_file(cache, content.as_bytes()).await {
+                            log::warn!("Failed to cache {} to {}: {}", source.name, cache.display(), e);
+                        }
+                    }
+                    return templates;
+                }
+            }
+            Err(e) => log::warn!("Failed to fetch {} from {}: {}", source.name, url, e),
+        }
+    }
+
+    for path in cache.iter().chain(&source.path) {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                if let Some(templates) = parse_logged(source, &path.display().to_string(), &content) {
+                    return templates;
+                }
+            }
+            // No cache yet is normal, the bundled file is next
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && Some(path) == cache.as_ref() => {}
+            Err(e) => log::warn!("Failed to read {} from {}: {}", source.name, path.display(), e),
+        }
+    }
+
+    log::error!("No {} available, those reminders won't be sent", source.name);
+    Vec::new()
+}
+
+pub async fn load_reminder_templates(
+    reminders: &TemplateSource,
+    acts: &TemplateSource,
+) -> (Vec<ReminderTemplate>, Vec<ReminderTemplateAct>) {
+    (load_template_source(reminders).await, load_template_source(acts).await)
+}
@@ -0,0 +1,335 @@
+use super::Storage;
+use crate::error::ScoreError;
+use crate::types::{CompletedQuiz, QuestionHistory, QuizSettings, SavedQuiz, ScoreEvent, UserReminderPreferences, UserScore};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const SCORES_FILE: &str = "user_scores.json";
+const PREFERENCES_FILE: &str = "user_preferences.json";
+const HISTORY_FILE: &str = "question_history.json";
+const QUIZ_SETTINGS_FILE: &str = "quiz_settings.json";
+const EVENTS_FILE: &str = "score_events.jsonl";
+const SESSIONS_FILE: &str = "quiz_sessions.jsonl";
+const ACTIVE_QUIZZES_FILE: &str = "active_quizzes.json";
+
+// How many previous versions of each JSON file are kept, as `<file>.1` (newest)
+// to `<file>.3`
+pub const BACKUP_COUNT: usize = 3;
+
+// Makes temporary file names unique within the process
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps each kind of data in its own JSON file in `dir`, in the same format
+/// the bot has always used. Logs are JSON Lines so adding to them never
+/// rewrites the file.
+///
+/// Files are replaced atomically: the new contents go to a uniquely named
+/// temporary file which is synced and then renamed over the old one, after the
+/// old one has been linked into the backups, so the file is never missing. If a
+/// file turns out to be unreadable on load, the newest readable backup is used
+/// instead. Temporary files left by a crash are removed on load.
+pub struct JsonStorage {
+    dir: PathBuf,
+    // One file replacement at a time, so backup rotation can't interleave
+    write_lock: Mutex<()>,
+}
+
+impl JsonStorage {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    async fn load_map<T: DeserializeOwned>(&self, file: &str) -> Result<HashMap<i64, T>, ScoreError> {
+        let path = self.path(file);
+        remove_stale_temp_files(&path).await;
+        let mut corrupt = None;
+        let mut read_error = None;
+
+        for candidate in std::iter::once(path.clone()).chain((1..=BACKUP_COUNT).map(|n| backup_path(&path, n))) {
+            let json = match fs::read_to_string(&candidate).await {
+                Ok(json) => json,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    log::error!("Failed to read {}: {}", candidate.display(), e);
+                    read_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            if json.trim().is_empty() {
+                log::warn!("{} is empty", candidate.display());
+                continue;
+            }
+            match serde_json::from_str(&json) {
+                Ok(map) => {
+                    if candidate != path {
+                        log::warn!("Recovered {} from backup {}", path.display(), candidate.display());
+                    }
+                    return Ok(map);
+                }
+                Err(e) => {
+                    log::error!("{} is unreadable: {}", candidate.display(), e);
+                    corrupt.get_or_insert(candidate);
+                }
+            }
+        }
+
+        // A file that couldn't be read may still hold good data, so don't start
+        // empty and have the next save replace it
+        if let Some(e) = read_error {
+            return Err(e.into());
+        }
+
+        // Nothing usable. Keep the damaged file for inspection, since the next
+        // save will rotate it away
+        if let Some(corrupt) = corrupt {
+            let kept = path.with_extension("json.corrupt");
+            fs::copy(&corrupt, &kept).await?;
+            log::error!("No readable copy of {}, starting empty. Damaged file kept as {}", path.display(), kept.display());
+        }
+        Ok(HashMap::new())
+    }
+
+    async fn save_map<T: Serialize + Sync>(&self, file: &str, map: &HashMap<i64, T>) -> Result<(), ScoreError> {
+        let json = serde_json::to_string_pretty(map)?;
+        self.write_atomic(&self.path(file), json.as_bytes()).await
+    }
+
+    async fn write_atomic(&self, path: &Path, contents: &[u8]) -> Result<(), ScoreError> {
+        let _guard = self.write_lock.lock().await;
+
+        let temp_path = write_temp_file(path, contents).await?;
+        let result = async {
+            rotate_backups(path).await?;
+            fs::rename(&temp_path, path).await?;
+            Ok::<_, std::io::Error>(())
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+        sync_dir(path).await;
+        Ok(())
+    }
+
+    async fn load_log<T: DeserializeOwned>(&self, file: &str) -> Result<Vec<T>, ScoreError> {
+        let path = self.path(file);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        Ok(parse_log(&path, &fs::read_to_string(&path).await?))
+    }
+
+    async fn append_log<T: Serialize + Sync>(&self, file: &str, entry: &T) -> Result<(), ScoreError> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut out = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(file))
+            .await?;
+        out.write_all(line.as_bytes()).await?;
+        out.sync_data().await?;
+        Ok(())
+    }
+}
+
+pub fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+// Writes `contents` to a uniquely named temporary file next to `path` and
+// syncs it, ready to be renamed into place
+async fn write_temp_file(path: &Path, contents: &[u8]) -> std::io::Result<PathBuf> {
+    let temp_path = temp_path(path);
+    let result = async {
+        let mut temp_file = File::create(&temp_path).await?;
+        temp_file.write_all(contents).await?;
+        temp_file.sync_all().await
+    }
+    .await;
+    match result {
+        Ok(()) => Ok(temp_path),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path).await;
+            Err(e)
+        }
+    }
+}
+
+/// Replaces the file at `path` with `contents` atomically, without backups.
+pub async fn replace_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temp_path = write_temp_file(path, contents).await?;
+    if let Err(e) = fs::rename(&temp_path, path).await {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+    sync_dir(path).await;
+    Ok(())
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name().and_then(|n| n.to_str()).unwrap_or("data")
+}
+
+// Next to the real file so the rename stays on one filesystem
+fn temp_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name(path),
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+// Removes temporary files of `path` left by a process that died before renaming
+// them into place. Loading happens before any saves start, so none are in use,
+// even ones with this process's pid (often 1 in a container).
+async fn remove_stale_temp_files(path: &Path) {
+    let dir = parent_dir(path);
+    let prefix = format!(".{}.", file_name(path));
+
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(&prefix) && name.ends_with(".tmp") {
+            match fs::remove_file(entry.path()).await {
+                Ok(()) => log::info!("Removed stale temporary file {}", entry.path().display()),
+                Err(e) => log::warn!("Failed to remove stale temporary file {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+}
+
+// Shifts <file>.1 -> <file>.2 and so on, dropping the oldest, then links the
+// current file as <file>.1. The current file stays in place until the new one
+// is renamed over it.
+async fn rotate_backups(path: &Path) -> std::io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    for n in (1..BACKUP_COUNT).rev() {
+        let from = backup_path(path, n);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, n + 1)).await?;
+        }
+    }
+    let newest = backup_path(path, 1);
+    if fs::hard_link(path, &newest).await.is_err() {
+        // Not every filesystem supports hard links
+        fs::copy(path, &newest).await?;
+    }
+    Ok(())
+}
+
+// Makes the renames themselves durable. Not every platform can open a
+// directory, so failures are only logged.
+async fn sync_dir(path: &Path) {
+    let dir = parent_dir(path);
+    let result = async { File::open(dir).await?.sync_all().await }.await;
+    if let Err(e) = result {
+        log::debug!("Could not sync directory {}: {}", dir.display(), e);
+    }
+}
+
+/// Reads a JSON Lines log. A line cut short by a crash mid-append is skipped
+/// rather than losing the whole log.
+pub fn parse_log<T: DeserializeOwned>(path: &Path, log: &str) -> Vec<T> {
+    log.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(index, line)| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("Skipping unreadable entry on line {} of {}: {}", index + 1, path.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[async_trait]
+impl Storage for JsonStorage {
+    async fn load_scores(&self) -> Result<HashMap<i64, UserScore>, ScoreError> {
+        self.load_map(SCORES_FILE).await
+    }
+
+    async fn save_scores(&self, scores: &HashMap<i64, UserScore>) -> Result<(), ScoreError> {
+        self.save_map(SCORES_FILE, scores).await
+    }
+
+    async fn load_preferences(&self) -> Result<HashMap<i64, UserReminderPreferences>, ScoreError> {
+        self.load_map(PREFERENCES_FILE).await
+    }
+
+    async fn save_preferences(&self, preferences: &HashMap<i64, UserReminderPreferences>) -> Result<(), ScoreError> {
+        self.save_map(PREFERENCES_FILE, preferences).await
+    }
+
+    async fn load_history(&self) -> Result<HashMap<i64, QuestionHistory>, ScoreError> {
+        self.load_map(HISTORY_FILE).await
+    }
+
+    async fn save_history(&self, history: &HashMap<i64, QuestionHistory>) -> Result<(), ScoreError> {
+        self.save_map(HISTORY_FILE, history).await
+    }
+
+    async fn load_quiz_settings(&self) -> Result<HashMap<i64, QuizSettings>, ScoreError> {
+        self.load_map(QUIZ_SETTINGS_FILE).await
+    }
+
+    async fn save_quiz_settings(&self, settings: &HashMap<i64, QuizSettings>) -> Result<(), ScoreError> {
+        self.save_map(QUIZ_SETTINGS_FILE, settings).await
+    }
+
+    async fn load_score_events(&self) -> Result<Vec<ScoreEvent>, ScoreError> {
+        self.load_log(EVENTS_FILE).await
+    }
+
+    async fn append_score_event(&self, event: &ScoreEvent) -> Result<(), ScoreError> {
+        self.append_log(EVENTS_FILE, event).await
+    }
+
+    async fn load_sessions(&self) -> Result<Vec<CompletedQuiz>, ScoreError> {
+        self.load_log(SESSIONS_FILE).await
+    }
+
+    async fn append_session(&self, quiz: &CompletedQuiz) -> Result<(), ScoreError> {
+        self.append_log(SESSIONS_FILE, quiz).await
+    }
+
+    async fn load_active_quizzes(&self) -> Result<HashMap<i64, SavedQuiz>, ScoreError> {
+        self.load_map(ACTIVE_QUIZZES_FILE).await
+    }
+
+    async fn save_active_quizzes(&self, quizzes: &HashMap<i64, SavedQuiz>) -> Result<(), ScoreError> {
+        self.save_map(ACTIVE_QUIZZES_FILE, quizzes).await
+    }
+}
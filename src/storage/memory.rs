@@ -0,0 +1,85 @@
+use super::Storage;
+use crate::error::ScoreError;
+use crate::types::{CompletedQuiz, QuestionHistory, QuizSettings, SavedQuiz, ScoreEvent, UserReminderPreferences, UserScore};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+
+/// Keeps everything in memory and forgets it on restart. Used by the tests and
+/// for throwaway deployments.
+#[derive(Default)]
+pub struct MemoryStorage {
+    scores: Mutex<HashMap<i64, UserScore>>,
+    preferences: Mutex<HashMap<i64, UserReminderPreferences>>,
+    history: Mutex<HashMap<i64, QuestionHistory>>,
+    quiz_settings: Mutex<HashMap<i64, QuizSettings>>,
+    score_events: Mutex<Vec<ScoreEvent>>,
+    sessions: Mutex<Vec<CompletedQuiz>>,
+    active_quizzes: Mutex<HashMap<i64, SavedQuiz>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn load_scores(&self) -> Result<HashMap<i64, UserScore>, ScoreError> {
+        Ok(self.scores.lock().await.clone())
+    }
+
+    async fn save_scores(&self, scores: &HashMap<i64, UserScore>) -> Result<(), ScoreError> {
+        *self.scores.lock().await = scores.clone();
+        Ok(())
+    }
+
+    async fn load_preferences(&self) -> Result<HashMap<i64, UserReminderPreferences>, ScoreError> {
+        Ok(self.preferences.lock().await.clone())
+    }
+
+    async fn save_preferences(&self, preferences: &HashMap<i64, UserReminderPreferences>) -> Result<(), ScoreError> {
+        *self.preferences.lock().await = preferences.clone();
+        Ok(())
+    }
+
+    async fn load_history(&self) -> Result<HashMap<i64, QuestionHistory>, ScoreError> {
+        Ok(self.history.lock().await.clone())
+    }
+
+    async fn save_history(&self, history: &HashMap<i64, QuestionHistory>) -> Result<(), ScoreError> {
+        *self.history.lock().await = history.clone();
+        Ok(())
+    }
+
+    async fn load_quiz_settings(&self) -> Result<HashMap<i64, QuizSettings>, ScoreError> {
+        Ok(self.quiz_settings.lock().await.clone())
+    }
+
+    async fn save_quiz_settings(&self, settings: &HashMap<i64, QuizSettings>) -> Result<(), ScoreError> {
+        *self.quiz_settings.lock().await = settings.clone();
+        Ok(())
+    }
+
+    async fn load_score_events(&self) -> Result<Vec<ScoreEvent>, ScoreError> {
+        Ok(self.score_events.lock().await.clone())
+    }
+
+    async fn append_score_event(&self, event: &ScoreEvent) -> Result<(), ScoreError> {
+        self.score_events.lock().await.push(event.clone());
+        Ok(())
+    }
+
+    async fn load_sessions(&self) -> Result<Vec<CompletedQuiz>, ScoreError> {
+        Ok(self.sessions.lock().await.clone())
+    }
+
+    async fn append_session(&self, quiz: &CompletedQuiz) -> Result<(), ScoreError> {
+        self.sessions.lock().await.push(quiz.clone());
+        Ok(())
+    }
+
+    async fn load_active_quizzes(&self) -> Result<HashMap<i64, SavedQuiz>, ScoreError> {
+        Ok(self.active_quizzes.lock().await.clone())
+    }
+
+    async fn save_active_quizzes(&self, quizzes: &HashMap<i64, SavedQuiz>) -> Result<(), ScoreError> {
+        *self.active_quizzes.lock().await = quizzes.clone();
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use crate::error::ScoreError;
+use crate::types::{CompletedQuiz, QuestionHistory, QuizSettings, SavedQuiz, ScoreEvent, UserReminderPreferences, UserScore};
+use async_trait::async_trait;
+use std::collections::HashMap;
+
+mod json;
+mod memory;
+mod sqlite;
+
+pub use json::*;
+pub use memory::*;
+pub use sqlite::*;
+
+/// Where the bot keeps everything it learns about its users. Handlers go
+/// through `BotState`, which holds one of these, and never touch files directly.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn load_scores(&self) -> Result<HashMap<i64, UserScore>, ScoreError>;
+    async fn save_scores(&self, scores: &HashMap<i64, UserScore>) -> Result<(), ScoreError>;
+
+    async fn load_preferences(&self) -> Result<HashMap<i64, UserReminderPreferences>, ScoreError>;
+    async fn save_preferences(&self, preferences: &HashMap<i64, UserReminderPreferences>) -> Result<(), ScoreError>;
+
+    async fn load_history(&self) -> Result<HashMap<i64, QuestionHistory>, ScoreError>;
+    async fn save_history(&self, history: &HashMap<i64, QuestionHistory>) -> Result<(), ScoreError>;
+
+    async fn load_quiz_settings(&self) -> Result<HashMap<i64, QuizSettings>, ScoreError>;
+    async fn save_quiz_settings(&self, settings: &HashMap<i64, QuizSettings>) -> Result<(), ScoreError>;
+
+    // Score events and finished quizzes are only ever added to, never rewritten
+    async fn load_score_events(&self) -> Result<Vec<ScoreEvent>, ScoreError>;
+    async fn append_score_event(&self, event: &ScoreEvent) -> Result<(), ScoreError>;
+
+    async fn load_sessions(&self) -> Result<Vec<CompletedQuiz>, ScoreError>;
+    async fn append_session(&self, quiz: &CompletedQuiz) -> Result<(), ScoreError>;
+
+    // Quizzes still running, keyed by chat id
+    async fn load_active_quizzes(&self) -> Result<HashMap<i64, SavedQuiz>, ScoreError>;
+    async fn save_active_quizzes(&self, quizzes: &HashMap<i64, SavedQuiz>) -> Result<(), ScoreError>;
+}
@@ -0,0 +1,558 @@
+use super::Storage;
+use crate::error::ScoreError;
+use crate::types::{CompletedQuiz, QuestionHistory, QuizSettings, SavedQuiz, ScoreEvent, UserReminderPreferences, UserScore};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Schema changes, applied in order. The number applied so far is kept in
+/// SQLite's `user_version`, so only append to this list.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema. Nested data is kept as JSON in `data`, with the
+    // columns worth querying pulled out beside it.
+    "CREATE TABLE users (
+        user_id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL
+    );
+    CREATE TABLE scores (
+        user_id INTEGER PRIMARY KEY REFERENCES users(user_id),
+        score INTEGER NOT NULL,
+        last_answer_time INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE reminder_preferences (
+        user_id INTEGER PRIMARY KEY REFERENCES users(user_id),
+        opted_in INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE question_history (
+        user_id INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE answer_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        username TEXT NOT NULL,
+        chat_id INTEGER,
+        question_id INTEGER NOT NULL,
+        is_correct INTEGER NOT NULL,
+        points INTEGER NOT NULL,
+        answered_at INTEGER NOT NULL
+    );
+    CREATE INDEX answer_history_answered_at ON answer_history(answered_at);
+    CREATE TABLE sessions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chat_id INTEGER NOT NULL,
+        finished_at INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );",
+    // 2: quizzes in progress, so they survive a restart
+    "CREATE TABLE active_quizzes (
+        chat_id INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );",
+    // 3: quiz settings, kept apart from the reminder preferences
+    "CREATE TABLE quiz_settings (
+        user_id INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );",
+    // 4: facts about the database itself. Databases that already hold data
+    // had the JSON files imported when they were created
+    "CREATE TABLE meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    INSERT INTO meta (key, value)
+    SELECT 'json_imported', 'before migration 4'
+    WHERE EXISTS (SELECT 1 FROM users)
+        OR EXISTS (SELECT 1 FROM question_history)
+        OR EXISTS (SELECT 1 FROM answer_history)
+        OR EXISTS (SELECT 1 FROM sessions);",
+    // 5: the category each answer counted towards. Older answers stay NULL
+    "ALTER TABLE answer_history ADD COLUMN category TEXT;",
+    // 6: reminder preferences are per chat, and groups aren't users, so they
+    // get their own key. Drop the users that were only added for a group
+    "CREATE TABLE chat_reminder_preferences (
+        chat_id INTEGER PRIMARY KEY,
+        opted_in INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    INSERT INTO chat_reminder_preferences (chat_id, opted_in, data)
+    SELECT user_id, opted_in, data FROM reminder_preferences;
+    DROP TABLE reminder_preferences;
+    ALTER TABLE chat_reminder_preferences RENAME TO reminder_preferences;
+    DELETE FROM users WHERE user_id < 0 AND user_id NOT IN (SELECT user_id FROM scores);",
+];
+
+// The `meta` key recording that the JSON files were imported
+const JSON_IMPORTED: &str = "json_imported";
+
+/// Keeps everything in a single SQLite database. Saving a map only writes the
+/// rows that changed since they were last loaded or saved.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+    // (table, row key) -> JSON last written, to skip unchanged rows
+    written: Mutex<HashMap<(&'static str, i64), String>>,
+}
+
+impl SqliteStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ScoreError> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut conn)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            written: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether the old JSON files still have to be imported. Only a finished
+    /// import is recorded, so one cut short by a crash runs again.
+    pub async fn needs_import(&self) -> Result<bool, ScoreError> {
+        self.with_conn(|conn| {
+            let imported: Option<String> = conn
+                .query_row("SELECT value FROM meta WHERE key = ?1", [JSON_IMPORTED], |row| row.get(0))
+                .optional()?;
+            Ok(imported.is_none())
+        })
+        .await
+    }
+
+    /// Copies everything from `from`, e.g. the old JSON files, in a single
+    /// transaction. The import is recorded in `meta` by the same transaction,
+    /// so it is marked done only once all of it has been committed.
+    pub async fn import_from(&self, from: &dyn Storage) -> Result<ImportSummary, ScoreError> {
+        let scores = from.load_scores().await?;
+        let preferences = from.load_preferences().await?;
+        let history = from.load_history().await?;
+        let quiz_settings = from.load_quiz_settings().await?;
+        let score_events = from.load_score_events().await?;
+        let sessions = from.load_sessions().await?;
+
+        let summary = ImportSummary {
+            scores: scores.len(),
+            preferences: preferences.len(),
+            histories: history.len(),
+            quiz_settings: quiz_settings.len(),
+            score_events: score_events.len(),
+            sessions: sessions.len(),
+        };
+
+        let score_rows = rows_with_values(self.changes("scores", &scores)?, &scores);
+        let preference_rows = rows_with_values(self.changes("reminder_preferences", &preferences)?, &preferences);
+        let history_rows = self.changes("question_history", &history)?;
+        let settings_rows = self.changes("quiz_settings", &quiz_settings)?;
+        let session_rows = sessions
+            .iter()
+            .map(|quiz| Ok((quiz.chat_id, quiz.finished_at.timestamp(), serde_json::to_string(quiz)?)))
+            .collect::<Result<Vec<_>, ScoreError>>()?;
+
+        let result = self
+            .with_conn(move |conn| {
+                let tx = conn.transaction()?;
+                write_scores(&tx, &score_rows.0, &score_rows.1)?;
+                write_preferences(&tx, &preference_rows.0, &preference_rows.1)?;
+                write_data_rows(&tx, "question_history", &history_rows)?;
+                write_data_rows(&tx, "quiz_settings", &settings_rows)?;
+                for event in &score_events {
+                    insert_score_event(&tx, event)?;
+                }
+                for (chat_id, finished_at, data) in &session_rows {
+                    insert_session(&tx, *chat_id, *finished_at, data)?;
+                }
+                tx.execute(
+                    "INSERT INTO meta (key, value) VALUES (?1, ?2)
+                     ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                    params![JSON_IMPORTED, Utc::now().to_rfc3339()],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await;
+        if result.is_err() {
+            for table in ["scores", "reminder_preferences", "question_history", "quiz_settings"] {
+                self.forget(table);
+            }
+        }
+        result.map(|()| summary)
+    }
+
+    // rusqlite blocks, so run each call off the async runtime
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, ScoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, ScoreError> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| ScoreError::IoError(std::io::Error::other(e)))?
+    }
+
+    async fn load_map<T: DeserializeOwned + Send + 'static>(&self, table: &'static str) -> Result<HashMap<i64, T>, ScoreError> {
+        let rows: Vec<(i64, String)> = self
+            .with_conn(move |conn| {
+                let mut stmt = conn.prepare(&format!("SELECT {}, data FROM {}", key_column(table), table))?;
+                let rows = stmt
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<_, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+
+        let mut written = self.written.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut map = HashMap::new();
+        for (user_id, data) in rows {
+            map.insert(user_id, serde_json::from_str(&data)?);
+            written.insert((table, user_id), data);
+        }
+        Ok(map)
+    }
+
+    /// Works out which rows of `table` differ from what was last written and
+    /// remembers the new contents.
+    fn changes<T: Serialize>(&self, table: &'static str, map: &HashMap<i64, T>) -> Result<RowChanges, ScoreError> {
+        let mut written = self.written.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut upserts = Vec::new();
+        for (user_id, value) in map {
+            let data = serde_json::to_string(value)?;
+            if written.get(&(table, *user_id)) != Some(&data) {
+                written.insert((table, *user_id), data.clone());
+                upserts.push((*user_id, data));
+            }
+        }
+
+        let deletes: Vec<i64> = written
+            .keys()
+            .filter(|(t, user_id)| *t == table && !map.contains_key(user_id))
+            .map(|(_, user_id)| *user_id)
+            .collect();
+        for user_id in &deletes {
+            written.remove(&(table, *user_id));
+        }
+        Ok(RowChanges { upserts, deletes })
+    }
+
+    // Called when a write fails, so the next save tries every row again
+    fn forget(&self, table: &'static str) {
+        let mut written = self.written.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        written.retain(|(t, _), _| *t != table);
+    }
+}
+
+struct RowChanges {
+    // (user id, JSON) of new or changed rows
+    upserts: Vec<(i64, String)>,
+    deletes: Vec<i64>,
+}
+
+impl RowChanges {
+    fn is_empty(&self) -> bool {
+        self.upserts.is_empty() && self.deletes.is_empty()
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), ScoreError> {
+    let applied: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        log::info!("Applied database migration {}", index + 1);
+    }
+    Ok(())
+}
+
+// Tables saved from a map are keyed by user, apart from the per-chat ones
+fn key_column(table: &str) -> &'static str {
+    match table {
+        "active_quizzes" | "reminder_preferences" => "chat_id",
+        _ => "user_id",
+    }
+}
+
+fn upsert_user(tx: &Transaction, user_id: i64, username: &str) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT INTO users (user_id, username) VALUES (?1, ?2)
+         ON CONFLICT(user_id) DO UPDATE SET username = excluded.username",
+        params![user_id, username],
+    )?;
+    Ok(())
+}
+
+fn delete_rows(tx: &Transaction, table: &str, deletes: &[i64]) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare(&format!("DELETE FROM {} WHERE {} = ?1", table, key_column(table)))?;
+    for user_id in deletes {
+        stmt.execute([user_id])?;
+    }
+    Ok(())
+}
+
+// Pairs each changed row with the value it was serialized from, for tables
+// that pull columns out of the value
+fn rows_with_values<T: Clone>(changes: RowChanges, map: &HashMap<i64, T>) -> (Vec<(T, String)>, Vec<i64>) {
+    let rows = changes
+        .upserts
+        .into_iter()
+        .filter_map(|(key, data)| map.get(&key).map(|value| (value.clone(), data)))
+        .collect();
+    (rows, changes.deletes)
+}
+
+fn write_scores(tx: &Transaction, rows: &[(UserScore, String)], deletes: &[i64]) -> rusqlite::Result<()> {
+    for (score, data) in rows {
+        upsert_user(tx, score.user_id, &score.username)?;
+        tx.execute(
+            "INSERT INTO scores (user_id, score, last_answer_time, data) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(user_id) DO UPDATE SET score = excluded.score,
+                 last_answer_time = excluded.last_answer_time, data = excluded.data",
+            params![score.user_id, score.score, score.last_answer_time.timestamp(), data],
+        )?;
+    }
+    delete_rows(tx, "scores", deletes)
+}
+
+fn write_preferences(tx: &Transaction, rows: &[(UserReminderPreferences, String)], deletes: &[i64]) -> rusqlite::Result<()> {
+    for (prefs, data) in rows {
+        // Keyed by chat, which may be a group, so not tied to a user
+        tx.execute(
+            "INSERT INTO reminder_preferences (chat_id, opted_in, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT(chat_id) DO UPDATE SET opted_in = excluded.opted_in, data = excluded.data",
+            params![prefs.user_id, prefs.opted_in, data],
+        )?;
+    }
+    delete_rows(tx, "reminder_preferences", deletes)
+}
+
+// For the tables that are just a key and the JSON
+fn write_data_rows(tx: &Transaction, table: &str, changes: &RowChanges) -> rusqlite::Result<()> {
+    let key = key_column(table);
+    let mut stmt = tx.prepare(&format!(
+        "INSERT INTO {table} ({key}, data) VALUES (?1, ?2)
+         ON CONFLICT({key}) DO UPDATE SET data = excluded.data"
+    ))?;
+    for (row_key, data) in &changes.upserts {
+        stmt.execute(params![row_key, data])?;
+    }
+    delete_rows(tx, table, &changes.deletes)
+}
+
+fn insert_score_event(conn: &Connection, event: &ScoreEvent) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO answer_history (user_id, username, chat_id, question_id, category, is_correct, points, answered_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            event.user_id,
+            event.username,
+            event.chat_id,
+            event.question_id,
+            event.category,
+            event.is_correct,
+            event.points,
+            event.at.timestamp()
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_session(conn: &Connection, chat_id: i64, finished_at: i64, data: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO sessions (chat_id, finished_at, data) VALUES (?1, ?2, ?3)",
+        params![chat_id, finished_at, data],
+    )?;
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn load_scores(&self) -> Result<HashMap<i64, UserScore>, ScoreError> {
+        self.load_map("scores").await
+    }
+
+    async fn save_scores(&self, scores: &HashMap<i64, UserScore>) -> Result<(), ScoreError> {
+        let (rows, deletes) = match self.changes("scores", scores)? {
+            changes if changes.is_empty() => return Ok(()),
+            changes => rows_with_values(changes, scores),
+        };
+
+        let result = self
+            .with_conn(move |conn| {
+                let tx = conn.transaction()?;
+                write_scores(&tx, &rows, &deletes)?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await;
+        if result.is_err() {
+            self.forget("scores");
+        }
+        result
+    }
+
+    async fn load_preferences(&self) -> Result<HashMap<i64, UserReminderPreferences>, ScoreError> {
+        self.load_map("reminder_preferences").await
+    }
+
+    async fn save_preferences(&self, preferences: &HashMap<i64, UserReminderPreferences>) -> Result<(), ScoreError> {
+        let (rows, deletes) = match self.changes("reminder_preferences", preferences)? {
+            changes if changes.is_empty() => return Ok(()),
+            changes => rows_with_values(changes, preferences),
+        };
+
+        let result = self
+            .with_conn(move |conn| {
+                let tx = conn.transaction()?;
+                write_preferences(&tx, &rows, &deletes)?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await;
+        if result.is_err() {
+            self.forget("reminder_preferences");
+        }
+        result
+    }
+
+    async fn load_history(&self) -> Result<HashMap<i64, QuestionHistory>, ScoreError> {
+        self.load_map("question_history").await
+    }
+
+    async fn save_history(&self, history: &HashMap<i64, QuestionHistory>) -> Result<(), ScoreError> {
+        let changes = match self.changes("question_history", history)? {
+            changes if changes.is_empty() => return Ok(()),
+            changes => changes,
+        };
+
+        let result = self
+            .with_conn(move |conn| {
+                let tx = conn.transaction()?;
+                write_data_rows(&tx, "question_history", &changes)?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await;
+        if result.is_err() {
+            self.forget("question_history");
+        }
+        result
+    }
+
+    async fn load_quiz_settings(&self) -> Result<HashMap<i64, QuizSettings>, ScoreError> {
+        self.load_map("quiz_settings").await
+    }
+
+    async fn save_quiz_settings(&self, settings: &HashMap<i64, QuizSettings>) -> Result<(), ScoreError> {
+        let changes = match self.changes("quiz_settings", settings)? {
+            changes if changes.is_empty() => return Ok(()),
+            changes => changes,
+        };
+
+        let result = self
+            .with_conn(move |conn| {
+                let tx = conn.transaction()?;
+                write_data_rows(&tx, "quiz_settings", &changes)?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await;
+        if result.is_err() {
+            self.forget("quiz_settings");
+        }
+        result
+    }
+
+    async fn load_score_events(&self) -> Result<Vec<ScoreEvent>, ScoreError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT user_id, username, chat_id, question_id, category, is_correct, points, answered_at
+                 FROM answer_history ORDER BY id",
+            )?;
+            let events = stmt
+                .query_map([], |row| {
+                    Ok(ScoreEvent {
+                        user_id: row.get(0)?,
+                        username: row.get(1)?,
+                        chat_id: row.get(2)?,
+                        question_id: row.get(3)?,
+                        category: row.get(4)?,
+                        is_correct: row.get(5)?,
+                        points: row.get(6)?,
+                        at: DateTime::from_timestamp(row.get(7)?, 0).unwrap_or_default(),
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(events)
+        })
+        .await
+    }
+
+    async fn append_score_event(&self, event: &ScoreEvent) -> Result<(), ScoreError> {
+        let event = event.clone();
+        self.with_conn(move |conn| Ok(insert_score_event(conn, &event)?)).await
+    }
+
+    async fn load_sessions(&self) -> Result<Vec<CompletedQuiz>, ScoreError> {
+        let rows: Vec<String> = self
+            .with_conn(|conn| {
+                let mut stmt = conn.prepare("SELECT data FROM sessions ORDER BY id")?;
+                let rows = stmt.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+        rows.iter()
+            .map(|data| serde_json::from_str(data).map_err(ScoreError::from))
+            .collect()
+    }
+
+    async fn load_active_quizzes(&self) -> Result<HashMap<i64, SavedQuiz>, ScoreError> {
+        self.load_map("active_quizzes").await
+    }
+
+    async fn save_active_quizzes(&self, quizzes: &HashMap<i64, SavedQuiz>) -> Result<(), ScoreError> {
+        let changes = match self.changes("active_quizzes", quizzes)? {
+            changes if changes.is_empty() => return Ok(()),
+            changes => changes,
+        };
+
+        let result = self
+            .with_conn(move |conn| {
+                let tx = conn.transaction()?;
+                write_data_rows(&tx, "active_quizzes", &changes)?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await;
+        if result.is_err() {
+            self.forget("active_quizzes");
+        }
+        result
+    }
+
+    async fn append_session(&self, quiz: &CompletedQuiz) -> Result<(), ScoreError> {
+        let data = serde_json::to_string(quiz)?;
+        let (chat_id, finished_at) = (quiz.chat_id, quiz.finished_at.timestamp());
+        self.with_conn(move |conn| Ok(insert_session(conn, chat_id, finished_at, &data)?)).await
+    }
+}
+
+/// How much `SqliteStorage::import_from` copied.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub scores: usize,
+    pub preferences: usize,
+    pub histories: usize,
+    pub quiz_settings: usize,
+    pub score_events: usize,
+    pub sessions: usize,
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+/// An achievement as described in achievements.json.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Achievement {
+    // Stored with each user once unlocked, so it must never change
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub badge: String,
+    pub rule: AchievementRule,
+}
+
+/// The condition that unlocks an achievement, e.g.
+/// `{"type": "category_answered", "category": "Hadith", "count": 100}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AchievementRule {
+    CorrectAnswers { count: u32 },
+    Points { points: u32 },
+    // Correct answers in a row within one quiz
+    AnswerStreak { count: u32 },
+    DailyStreak { days: u32 },
+    CategoryAnswered { category: String, count: u32 },
+    // Every question of a finished quiz of at least `min_questions` right
+    PerfectQuiz { min_questions: u32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnlockedAchievement {
+    pub id: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub unlocked_at: DateTime<Utc>,
+}
@@ -1,18 +1,25 @@
-use serde::{Deserialize, Serialize};
-use chrono::{DateTime, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use chrono::{DateTime, Days, NaiveDate, Utc};
 use teloxide::types::MessageId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
 
 mod reminder;
+mod quiz;
+mod achievement;
 pub use reminder::*;
+pub use quiz::*;
+pub use achievement::*;
 
-#[derive(Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum GameState {
     InProgress { questions_asked: u32, max_questions: u32 },
-    Ended,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Question {
+    pub id: u32,
     pub question: String,
     pub correct_answer: String,
     pub option1: String,
@@ -20,7 +27,30 @@ pub struct Question {
     pub option3: String,
     pub option4: String,
     pub category: String,
+    // Left empty in the CSV (read as 0) to use the difficulty's default points
+    #[serde(default, deserialize_with = "deserialize_points")]
     pub points: u32,
+    #[serde(default, deserialize_with = "deserialize_difficulty")]
+    pub difficulty: Option<Difficulty>,
+}
+
+fn deserialize_points<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(0);
+    }
+    value.parse().map_err(de::Error::custom)
+}
+
+fn deserialize_difficulty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Difficulty>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    Difficulty::parse(&value)
+        .map(Some)
+        .ok_or_else(|| de::Error::custom(format!("unknown difficulty {:?}, expected easy, medium or hard", value)))
 }
 
 impl Question {
@@ -32,6 +62,17 @@ impl Question {
             self.option4.clone(),
         ]
     }
+
+    pub fn is_correct_option(&self, option_index: usize) -> bool {
+        self.get_options()
+            .get(option_index)
+            .is_some_and(|option| option == &self.correct_answer)
+    }
+
+    // Questions without a difficulty in the CSV count as medium
+    pub fn difficulty(&self) -> Difficulty {
+        self.difficulty.unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,11 +82,377 @@ pub struct UserScore {
     pub score: u32,
     #[serde(with = "chrono::serde::ts_seconds")]
     pub last_answer_time: DateTime<Utc>,
+    // Longest run of correct answers within a single quiz
+    #[serde(default)]
+    pub best_answer_streak: u32,
+    // Consecutive days with at least one answer
+    #[serde(default)]
+    pub daily_streak: u32,
+    #[serde(default)]
+    pub best_daily_streak: u32,
+    #[serde(default)]
+    pub last_play_date: Option<NaiveDate>,
+    // Category name -> how the user is doing in it
+    #[serde(default)]
+    pub categories: HashMap<String, CategoryStats>,
+    // In the order they were earned
+    #[serde(default)]
+    pub achievements: Vec<UnlockedAchievement>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CategoryStats {
+    pub answered: u32,
+    pub correct: u32,
+    pub points: u32,
+    // Distinct questions answered correctly at least once
+    #[serde(default)]
+    pub mastered_questions: HashSet<u32>,
+}
+
+impl CategoryStats {
+    pub fn record_answer(&mut self, question_id: u32, is_correct: bool, points: u32) {
+        self.answered += 1;
+        if is_correct {
+            self.correct += 1;
+            self.points += points;
+            self.mastered_questions.insert(question_id);
+        }
+    }
+
+    pub fn accuracy(&self) -> u32 {
+        if self.answered == 0 {
+            return 0;
+        }
+        self.correct * 100 / self.answered
+    }
+
+    // Share of the category's questions the user has got right at least once
+    pub fn mastery(&self, category_size: usize) -> u32 {
+        if category_size == 0 {
+            return 0;
+        }
+        (self.mastered_questions.len().min(category_size) * 100 / category_size) as u32
+    }
+}
+
+impl UserScore {
+    pub fn new(user_id: i64, username: String) -> Self {
+        Self {
+            user_id,
+            username,
+            score: 0,
+            last_answer_time: Utc::now(),
+            best_answer_streak: 0,
+            daily_streak: 0,
+            best_daily_streak: 0,
+            last_play_date: None,
+            categories: HashMap::new(),
+            achievements: Vec::new(),
+        }
+    }
+
+    pub fn has_achievement(&self, id: &str) -> bool {
+        self.achievements.iter().any(|a| a.id == id)
+    }
+
+    pub fn record_category_answer(&mut self, category: &str, question_id: u32, is_correct: bool, points: u32) {
+        self.categories
+            .entry(category.to_string())
+            .or_default()
+            .record_answer(question_id, is_correct, points);
+    }
+
+    pub fn record_play(&mut self, today: NaiveDate) {
+        if self.last_play_date == Some(today) {
+            return;
+        }
+        let played_yesterday = self.last_play_date.is_some_and(|last| last.checked_add_days(Days::new(1)) == Some(today));
+        self.daily_streak = if played_yesterday { self.daily_streak + 1 } else { 1 };
+        self.best_daily_streak = self.best_daily_streak.max(self.daily_streak);
+        self.last_play_date = Some(today);
+    }
+
+    // The stored streak only counts while the user hasn't missed a day
+    pub fn current_daily_streak(&self, today: NaiveDate) -> u32 {
+        match self.last_play_date {
+            Some(last) if last == today || last.checked_add_days(Days::new(1)) == Some(today) => self.daily_streak,
+            _ => 0,
+        }
+    }
+}
+
+// One answer's contribution to the leaderboards, appended to the event log and
+// never rewritten
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreEvent {
+    pub user_id: i64,
+    pub username: String,
+    // Chat the answer was given in, for group leaderboards
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    pub question_id: u32,
+    // Category of the question when it was answered, so later edits to the
+    // question bank don't move points between category leaderboards
+    #[serde(default)]
+    pub category: Option<String>,
+    pub is_correct: bool,
+    pub points: u32,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub at: DateTime<Utc>,
+}
+
+// Per-member tally for the quiz currently running in a chat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuizParticipant {
+    pub username: String,
+    pub answered: u32,
+    pub correct: u32,
+    pub points: u32,
+    // Consecutive correct answers in this quiz
+    pub streak: u32,
+    pub best_streak: u32,
+}
+
+impl QuizParticipant {
+    pub fn new(username: String) -> Self {
+        Self {
+            username,
+            answered: 0,
+            correct: 0,
+            points: 0,
+            streak: 0,
+            best_streak: 0,
+        }
+    }
+
+    pub fn record_answer(&mut self, is_correct: bool, points: u32) {
+        self.answered += 1;
+        if is_correct {
+            self.correct += 1;
+            self.points += points;
+            self.streak += 1;
+            self.best_streak = self.best_streak.max(self.streak);
+        } else {
+            self.streak = 0;
+        }
+    }
+}
+
+// Questions a user has already been dealt, so quizzes don't repeat until the pool runs out
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QuestionHistory {
+    pub user_id: i64,
+    pub seen_questions: HashSet<u32>,
+    // Most recent answers in adaptive quizzes, all at `adaptive_level`, newest last
+    #[serde(default)]
+    pub recent_answers: VecDeque<bool>,
+    #[serde(default)]
+    pub adaptive_level: Difficulty,
+}
+
+impl QuestionHistory {
+    pub const RECENT_ANSWERS_WINDOW: usize = 10;
+
+    pub fn new(user_id: i64) -> Self {
+        Self {
+            user_id,
+            seen_questions: HashSet::new(),
+            recent_answers: VecDeque::new(),
+            adaptive_level: Difficulty::default(),
+        }
+    }
+
+    pub fn record_answer(&mut self, is_correct: bool) {
+        self.recent_answers.push_back(is_correct);
+        while self.recent_answers.len() > Self::RECENT_ANSWERS_WINDOW {
+            self.recent_answers.pop_front();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionAnswer {
+    pub user_id: i64,
+    pub question_id: u32,
+    pub option_index: usize,
+    pub is_correct: bool,
+    pub points: u32,
+}
+
+// Everything that happened in one quiz, carried from question to question
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuizSession {
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub started_at: DateTime<Utc>,
+    // Question ids in the order they were asked
+    pub questions: Vec<u32>,
+    pub answers: Vec<SessionAnswer>,
+    // user_id -> results across the whole quiz
+    pub participants: HashMap<i64, QuizParticipant>,
+}
+
+impl QuizSession {
+    pub fn new(first_question_id: u32) -> Self {
+        Self {
+            started_at: Utc::now(),
+            questions: vec![first_question_id],
+            answers: Vec::new(),
+            participants: HashMap::new(),
+        }
+    }
+
+    pub fn correct_count(&self, user_id: i64) -> u32 {
+        self.participants.get(&user_id).map_or(0, |p| p.correct)
+    }
+
+    pub fn points_earned(&self, user_id: i64) -> u32 {
+        self.answers.iter().filter(|a| a.user_id == user_id).map(|a| a.points).sum()
+    }
+
+    // The option the user picked for a question, if they answered it
+    pub fn chosen_option(&self, user_id: i64, question_id: u32) -> Option<usize> {
+        self.answers
+            .iter()
+            .find(|a| a.user_id == user_id && a.question_id == question_id)
+            .map(|a| a.option_index)
+    }
+
+    // Share of the asked questions the user got right, as a percentage
+    pub fn accuracy(&self, user_id: i64) -> u32 {
+        if self.questions.is_empty() {
+            return 0;
+        }
+        self.correct_count(user_id) * 100 / self.questions.len() as u32
+    }
+
+    /// Asked questions that `user_id` didn't get right, or that nobody got
+    /// right when `user_id` is None, in the order they were asked.
+    pub fn missed_questions(&self, user_id: Option<i64>) -> Vec<u32> {
+        self.questions
+            .iter()
+            .copied()
+            .filter(|question_id| {
+                !self.answers.iter().any(|a| {
+                    a.question_id == *question_id && a.is_correct && user_id.is_none_or(|u| a.user_id == u)
+                })
+            })
+            .collect()
+    }
+}
+
+// A finished quiz as kept in storage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletedQuiz {
+    pub chat_id: i64,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub finished_at: DateTime<Utc>,
+    pub session: QuizSession,
+}
+
+/// An in-progress quiz as kept in storage, so it survives a restart. Times are
+/// wall-clock since `Instant`s mean nothing to the next process.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedQuiz {
+    pub quiz_id: u32,
+    pub question_id: u32,
+    pub message_id: MessageId,
+    pub game_state: GameState,
+    pub remaining_questions: Vec<u32>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub asked_at: DateTime<Utc>,
+    pub time_limit_secs: Option<u64>,
+    #[serde(default)]
+    pub adaptive: bool,
+    #[serde(default)]
+    pub expected_players: HashSet<i64>,
+    pub answers: HashMap<i64, usize>,
+    pub session: QuizSession,
 }
 
 #[derive(Clone)]
 pub struct ActiveQuestion {
-    pub question: Question,
+    // Random per-quiz id so buttons from an earlier quiz can't answer this one
+    pub quiz_id: u32,
+    pub question_id: u32,
     pub message_id: MessageId,
     pub game_state: GameState,
+    // Question ids still to be asked in this quiz, dealt up front
+    pub remaining_questions: Vec<u32>,
+    // When the current question was sent, for expiry and speed bonuses
+    pub asked_at: Instant,
+    pub time_limit: Option<Duration>,
+    // Whether answers feed the players' adaptive difficulty
+    pub adaptive: bool,
+    // Members who answered the previous question. Empty for the first question
+    pub expected_players: HashSet<i64>,
+    // user_id -> index of the option chosen for the current question, one answer per member
+    pub answers: HashMap<i64, usize>,
+    pub session: QuizSession,
+}
+
+impl ActiveQuestion {
+    pub fn new(quiz_id: u32, question_id: u32, message_id: MessageId, game_state: GameState) -> Self {
+        Self {
+            quiz_id,
+            question_id,
+            message_id,
+            game_state,
+            remaining_questions: Vec::new(),
+            asked_at: Instant::now(),
+            time_limit: None,
+            adaptive: false,
+            expected_players: HashSet::new(),
+            answers: HashMap::new(),
+            session: QuizSession::new(question_id),
+        }
+    }
+
+    pub fn to_saved(&self, now: DateTime<Utc>) -> SavedQuiz {
+        let elapsed = chrono::Duration::from_std(self.asked_at.elapsed()).unwrap_or_default();
+        SavedQuiz {
+            quiz_id: self.quiz_id,
+            question_id: self.question_id,
+            message_id: self.message_id,
+            game_state: self.game_state.clone(),
+            remaining_questions: self.remaining_questions.clone(),
+            asked_at: now - elapsed,
+            time_limit_secs: self.time_limit.map(|limit| limit.as_secs()),
+            adaptive: self.adaptive,
+            expected_players: self.expected_players.clone(),
+            answers: self.answers.clone(),
+            session: self.session.clone(),
+        }
+    }
+
+    // The time spent while the bot was down counts against the question's limit
+    pub fn from_saved(saved: SavedQuiz, now: DateTime<Utc>) -> Self {
+        let elapsed = (now - saved.asked_at).to_std().unwrap_or_default();
+        Self {
+            quiz_id: saved.quiz_id,
+            question_id: saved.question_id,
+            message_id: saved.message_id,
+            game_state: saved.game_state,
+            remaining_questions: saved.remaining_questions,
+            asked_at: Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now),
+            time_limit: saved.time_limit_secs.map(Duration::from_secs),
+            adaptive: saved.adaptive,
+            expected_players: saved.expected_players,
+            answers: saved.answers,
+            session: saved.session,
+        }
+    }
+
+    pub fn is_last_question(&self) -> bool {
+        let GameState::InProgress { questions_asked, max_questions } = self.game_state;
+        questions_asked >= max_questions || self.remaining_questions.is_empty()
+    }
+
+    // Everyone who played the previous question has had their go at this one.
+    // A correct answer closes it straight away, so they must all have been wrong.
+    // The first question has nobody to wait for, so it stays open until someone
+    // gets it right, the timer runs out or the quiz is ended.
+    pub fn all_expected_players_answered(&self) -> bool {
+        !self.expected_players.is_empty() && self.expected_players.iter().all(|user_id| self.answers.contains_key(user_id))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+pub const DEFAULT_QUIZ_LENGTH: u32 = 5;
+pub const MIN_QUIZ_LENGTH: u32 = 1;
+pub const MAX_QUIZ_LENGTH: u32 = 20;
+pub const MIN_TIME_LIMIT_SECS: u32 = 10;
+pub const MAX_TIME_LIMIT_SECS: u32 = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 3] = [Difficulty::Easy, Difficulty::Medium, Difficulty::Hard];
+
+    // Used for questions whose `points` column is left empty
+    pub fn points(self) -> u32 {
+        match self {
+            Difficulty::Easy => 5,
+            Difficulty::Medium => 10,
+            Difficulty::Hard => 20,
+        }
+    }
+
+    pub fn harder(self) -> Self {
+        match self {
+            Difficulty::Easy => Difficulty::Medium,
+            Difficulty::Medium | Difficulty::Hard => Difficulty::Hard,
+        }
+    }
+
+    pub fn easier(self) -> Self {
+        match self {
+            Difficulty::Hard => Difficulty::Medium,
+            Difficulty::Medium | Difficulty::Easy => Difficulty::Easy,
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.trim().to_lowercase().as_str() {
+            "easy" => Some(Difficulty::Easy),
+            "medium" => Some(Difficulty::Medium),
+            "hard" => Some(Difficulty::Hard),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difficulty::Easy => write!(f, "Easy"),
+            Difficulty::Medium => write!(f, "Medium"),
+            Difficulty::Hard => write!(f, "Hard"),
+        }
+    }
+}
+
+// A user's default quiz, used by /question when no arguments are given
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuizSettings {
+    pub length: u32,
+    pub category: Option<String>,
+    pub difficulty: Option<Difficulty>,
+    // Pick the difficulty from the user's recent accuracy instead of `difficulty`
+    #[serde(default)]
+    pub adaptive: bool,
+    // Seconds allowed per question, None for untimed quizzes
+    #[serde(default)]
+    pub time_limit_secs: Option<u32>,
+}
+
+impl Default for QuizSettings {
+    fn default() -> Self {
+        Self {
+            length: DEFAULT_QUIZ_LENGTH,
+            category: None,
+            difficulty: None,
+            adaptive: false,
+            time_limit_secs: None,
+        }
+    }
+}
+
+impl QuizSettings {
+    pub fn with_length(mut self, length: u32) -> Self {
+        self.length = length.clamp(MIN_QUIZ_LENGTH, MAX_QUIZ_LENGTH);
+        self
+    }
+
+    pub fn with_time_limit(mut self, secs: Option<u32>) -> Self {
+        self.time_limit_secs = secs.map(|secs| secs.clamp(MIN_TIME_LIMIT_SECS, MAX_TIME_LIMIT_SECS));
+        self
+    }
+
+    pub fn time_limit(&self) -> Option<Duration> {
+        self.time_limit_secs.map(|secs| Duration::from_secs(secs as u64))
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "Questions: {}\nCategory: {}\nDifficulty: {}\nTime limit: {}",
+            self.length,
+            self.category.as_deref().unwrap_or("Any"),
+            if self.adaptive {
+                "Adaptive".to_string()
+            } else {
+                self.difficulty.map_or("Any".to_string(), |d| d.to_string())
+            },
+            self.time_limit_secs.map_or("Off".to_string(), |secs| format!("{}s", secs))
+        )
+    }
+}
+
+/// Extra points for answering a timed question quickly: up to half the
+/// question's points for an instant answer, falling to nothing at the limit.
+pub fn speed_bonus(points: u32, latency: Duration, time_limit: Duration) -> u32 {
+    if time_limit.is_zero() || latency >= time_limit {
+        return 0;
+    }
+    let remaining = 1.0 - latency.as_secs_f64() / time_limit.as_secs_f64();
+    (points as f64 / 2.0 * remaining).floor() as u32
+}
+
+/// Percentage of a question's points awarded for the given run of correct
+/// answers: x1.5 from three in a row, x2 from five.
+pub fn streak_multiplier(streak: u32) -> u32 {
+    match streak {
+        0..=2 => 100,
+        3..=4 => 150,
+        _ => 200,
+    }
+}
+
+pub fn apply_streak_multiplier(points: u32, streak: u32) -> u32 {
+    points * streak_multiplier(streak) / 100
+}
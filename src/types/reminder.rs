@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use crate::scheduler::{CronRule, Scheduler};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ReminderTemplate {
@@ -21,12 +23,257 @@ pub struct ReminderTemplateAct {
 
 
 
+// Named delivery times accepted by /schedule, in the user's local time. These
+// are fixed clock times, not prayer times
+pub const NAMED_DELIVERY_TIMES: &[(&str, u32, u32)] = &[
+    ("early", 6, 0),
+    ("morning", 8, 0),
+    ("midday", 13, 0),
+    ("afternoon", 16, 0),
+    ("evening", 19, 0),
+    ("night", 21, 30),
+];
+
+pub const MAX_DELIVERY_TIMES: usize = 6;
+
+// The closest together /schedule times or a cron rule may send reminders
+pub const MIN_CRON_INTERVAL: Duration = Duration::hours(1);
+
+const MAX_QUIET_SKIPS: usize = 1000;
+
+/// A span of local time in which no reminders are sent. It wraps past
+/// midnight when `end` is before `start`, e.g. 22:00-07:00.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// When a user gets their reminders, in their own timezone.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReminderSchedule {
+    pub timezone: Tz,
+    pub delivery_times: Vec<NaiveTime>,
+    pub quiet_hours: Option<QuietHours>,
+    // Replaces the delivery times when set
+    #[serde(default)]
+    pub cron: Option<CronRule>,
+}
+
+impl Default for ReminderSchedule {
+    // Four a day, as before per-user schedules
+    fn default() -> Self {
+        let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        Self {
+            timezone: chrono_tz::UTC,
+            delivery_times: vec![at(6, 0), at(12, 0), at(18, 0), at(21, 0)],
+            quiet_hours: None,
+            cron: None,
+        }
+    }
+}
+
+impl ReminderSchedule {
+    /// The first delivery time strictly after `after` that falls outside quiet
+    /// hours, or `None` if quiet hours cover every delivery time. Times skipped
+    /// by a DST change are moved past the gap.
+    pub fn next_due(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if let Some(cron) = &self.cron {
+            return self.next_cron_due(cron, after);
+        }
+
+        let today = after.with_timezone(&self.timezone).date_naive();
+        let times: Vec<NaiveTime> = self
+            .delivery_times
+            .iter()
+            .copied()
+            .filter(|time| !self.quiet_hours.is_some_and(|quiet| quiet.contains(*time)))
+            .collect();
+
+        (0..=2)
+            .filter_map(|days| today.checked_add_signed(Duration::days(days)))
+            .flat_map(|date| times.iter().map(move |time| date.and_time(*time)))
+            .filter_map(|local| {
+                self.timezone
+                    .from_local_datetime(&local)
+                    .earliest()
+                    .or_else(|| self.timezone.from_local_datetime(&(local + Duration::hours(1))).earliest())
+            })
+            .map(|due| due.with_timezone(&Utc))
+            .filter(|due| *due > after)
+            .min()
+    }
+
+    fn next_cron_due(&self, cron: &CronRule, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut due = cron.next_after(after, self.timezone)?;
+        // Give up on rules that only ever match inside quiet hours
+        for _ in 0..MAX_QUIET_SKIPS {
+            let local = due.with_timezone(&self.timezone).time();
+            if !self.quiet_hours.is_some_and(|quiet| quiet.contains(local)) {
+                return Some(due);
+            }
+            due = cron.next_after(due, self.timezone)?;
+        }
+        None
+    }
+}
+
+/// The reminder series a user can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReminderStream {
+    // `ReminderTemplate`: a dua with Arabic, transliteration and translation
+    Duas,
+    // `ReminderTemplateAct`: a sunnah act to practise
+    SunnahActs,
+}
+
+impl ReminderStream {
+    pub const ALL: [ReminderStream; 2] = [ReminderStream::Duas, ReminderStream::SunnahActs];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ReminderStream::Duas => "Duas",
+            ReminderStream::SunnahActs => "Sunnah acts",
+        }
+    }
+
+    // Each dua is repeated for a week to help it stick; acts change daily
+    fn same_template_period(self, a: NaiveDate, b: NaiveDate) -> bool {
+        match self {
+            ReminderStream::Duas => a.iso_week() == b.iso_week(),
+            ReminderStream::SunnahActs => a == b,
+        }
+    }
+}
+
+/// How often a subscribed stream goes out, out of the user's delivery times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReminderFrequency {
+    #[default]
+    EveryDelivery,
+    // The first delivery time of each local day
+    Daily,
+    // The first delivery time of each local week, starting Monday
+    Weekly,
+}
+
+impl ReminderFrequency {
+    pub const ALL: [ReminderFrequency; 3] =
+        [ReminderFrequency::EveryDelivery, ReminderFrequency::Daily, ReminderFrequency::Weekly];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ReminderFrequency::EveryDelivery => "Every reminder",
+            ReminderFrequency::Daily => "Daily",
+            ReminderFrequency::Weekly => "Weekly",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamSubscription {
+    pub enabled: bool,
+    #[serde(default)]
+    pub frequency: ReminderFrequency,
+    #[serde(default)]
+    pub last_sent: Option<DateTime<Utc>>,
+    // Index of the template the user is on in this series
+    #[serde(default)]
+    pub position: usize,
+    // When the user first got the template at `position`
+    #[serde(default)]
+    pub position_since: Option<DateTime<Utc>>,
+}
+
+impl Default for StreamSubscription {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            frequency: ReminderFrequency::default(),
+            last_sent: None,
+            position: 0,
+            position_since: None,
+        }
+    }
+}
+
+impl StreamSubscription {
+    fn is_due(&self, now: DateTime<Utc>, timezone: Tz) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let Some(last_sent) = self.last_sent else {
+            return true;
+        };
+        let (last, now) = (last_sent.with_timezone(&timezone).date_naive(), now.with_timezone(&timezone).date_naive());
+        match self.frequency {
+            ReminderFrequency::EveryDelivery => true,
+            ReminderFrequency::Daily => last != now,
+            ReminderFrequency::Weekly => last.iso_week() != now.iso_week(),
+        }
+    }
+}
+
+/// Which streams a user gets; both are on for everyone who opted in before
+/// subscriptions existed.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct ReminderSubscriptions {
+    #[serde(default)]
+    pub duas: StreamSubscription,
+    #[serde(default)]
+    pub sunnah_acts: StreamSubscription,
+}
+
+impl ReminderSubscriptions {
+    pub fn get(&self, stream: ReminderStream) -> &StreamSubscription {
+        match stream {
+            ReminderStream::Duas => &self.duas,
+            ReminderStream::SunnahActs => &self.sunnah_acts,
+        }
+    }
+
+    pub fn get_mut(&mut self, stream: ReminderStream) -> &mut StreamSubscription {
+        match stream {
+            ReminderStream::Duas => &mut self.duas,
+            ReminderStream::SunnahActs => &mut self.sunnah_acts,
+        }
+    }
+
+    // Back to the first template of every series, keeping the user's choices
+    pub fn restart_series(&mut self) {
+        for stream in ReminderStream::ALL {
+            let subscription = self.get_mut(stream);
+            subscription.position = 0;
+            subscription.position_since = None;
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserReminderPreferences {
     pub user_id: i64,
     pub username: String,
     pub opted_in: bool,
     pub last_reminder: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub schedule: ReminderSchedule,
+    // Worked out from the schedule; cleared whenever the schedule changes
+    #[serde(default)]
+    pub next_reminder: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub subscriptions: ReminderSubscriptions,
 }
 
 impl UserReminderPreferences {
@@ -36,6 +283,71 @@ impl UserReminderPreferences {
             username,
             opted_in: false,
             last_reminder: None,
+            schedule: ReminderSchedule::default(),
+            next_reminder: None,
+            subscriptions: ReminderSubscriptions::default(),
+        }
+    }
+
+    /// The streams to send now, each with the delivery time it is for, moving
+    /// `next_reminder` on if a delivery time has come. Delivery times missed
+    /// while the bot was down follow the scheduler's catch-up policy. Users
+    /// without a next time yet get one and wait for it.
+    pub fn take_due(&mut self, scheduler: &Scheduler) -> Vec<(DateTime<Utc>, ReminderStream)> {
+        if !self.opted_in {
+            return Vec::new();
+        }
+        let now = scheduler.now();
+        match self.next_reminder {
+            Some(due) if due <= now => {
+                let schedule = &self.schedule;
+                let due = scheduler.due_runs(due, |run| schedule.next_due(run));
+                self.next_reminder = due.next_run;
+
+                let timezone = self.schedule.timezone;
+                let mut streams = Vec::new();
+                for run in due.runs {
+                    for stream in ReminderStream::ALL {
+                        let subscription = self.subscriptions.get_mut(stream);
+                        if subscription.is_due(run, timezone) {
+                            subscription.last_sent = Some(run);
+                            streams.push((run, stream));
+                        }
+                    }
+                }
+                if !streams.is_empty() {
+                    self.last_reminder = Some(now);
+                }
+                streams
+            }
+            Some(_) => Vec::new(),
+            None => {
+                self.next_reminder = self.schedule.next_due(now);
+                Vec::new()
+            }
+        }
+    }
+
+    /// The template of `stream` to send at `now`, out of `series_len`. The user
+    /// moves to the next template once its period is over, and back to the
+    /// first after the last one.
+    pub fn template_position(&mut self, stream: ReminderStream, now: DateTime<Utc>, series_len: usize) -> Option<usize> {
+        if series_len == 0 {
+            return None;
+        }
+        let timezone = self.schedule.timezone;
+        let subscription = self.subscriptions.get_mut(stream);
+        let today = now.with_timezone(&timezone).date_naive();
+        match subscription.position_since {
+            Some(since) if !stream.same_template_period(since.with_timezone(&timezone).date_naive(), today) => {
+                subscription.position += 1;
+                subscription.position_since = Some(now);
+            }
+            Some(_) => {}
+            None => subscription.position_since = Some(now),
         }
+        // Also covers a series that got shorter since the position was saved
+        subscription.position %= series_len;
+        Some(subscription.position)
     }
 }
@@ -1,16 +1,17 @@
 #[cfg(test)]
 mod tests {
-    use islamic_trivia_quiz_bot::*;
+    use islamic_trivia_bot::*;
     use std::error::Error;
-    use teloxide::types::InlineKeyboardMarkup;
+    use teloxide::types::{InlineKeyboardMarkup, MessageId};
+    use std::time::Duration;
     // use std::path::PathBuf;
     use tempfile::NamedTempFile;
     use std::io::Write;
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet, VecDeque};
     use tokio::sync::Mutex;
     use std::sync::Arc;
-    use chrono::{Utc, DateTime};
-    use rand::{SeedableRng, rngs::StdRng, seq::IteratorRandom};
+    use chrono::{NaiveDate, Utc};
+    use rand::{SeedableRng, rngs::StdRng};
 
 
     // Helper function to create a test question
@@ -25,10 +26,30 @@ mod tests {
             option4: String::from("Madrid"),
             category: String::from("Geography"),
             points: 10,
+            difficulty: None,
+        }
+    }
+
+    // Helper function to create an empty bot state around the given questions
+    fn create_test_state(questions: Vec<Question>) -> BotState {
+        BotState {
+            questions,
+            active_questions: Mutex::new(HashMap::new()),
+            user_scores: Mutex::new(HashMap::new()),
+            rng: Mutex::new(StdRng::seed_from_u64(1)),
+            reminder_templates: Vec::new(),
+            reminder_templates_act: Vec::new(),
+            user_preferences: Mutex::new(HashMap::new()),
+            question_history: Mutex::new(HashMap::new()),
+            score_events: Mutex::new(Vec::new()),
+            quiz_settings: Mutex::new(HashMap::new()),
+            leaderboard_timezone: chrono_tz::UTC,
+            achievements: Vec::new(),
+            storage: Arc::new(MemoryStorage::default()),
+            dirty: DirtyFlags::default(),
         }
     }
 
-    #[warn(dead_code)]
     // Helper function to create a temporary CSV file with test questions
     fn create_test_csv() -> Result<NamedTempFile, Box<dyn Error>> {
         let mut temp_file = NamedTempFile::new()?;
@@ -67,87 +88,422 @@ mod tests {
     #[test]
     fn test_create_keyboard_initial() {
         let question = create_test_question();
-        let keyboard = create_keyboard(&question, None, false, true);
+        let keyboard = create_keyboard(&question, 42, None, false, true);
         
         // Check that keyboard has correct number of buttons
-        if let InlineKeyboardMarkup { inline_keyboard } = keyboard {
-            assert_eq!(inline_keyboard.len(), 5); // 4 options + End Button
-
-            let button_text = &inline_keyboard[4][0].text;
-            assert!(button_text.contains("🛑"));
-            
-            for row in inline_keyboard {
-                eprintln!("row: {:?}", row);
-                assert_eq!(row.len(), 1);
-                let button_text = &row[0].text;
-                assert!(!button_text.contains("✅"));
-                assert!(!button_text.contains("❌"));
-            }
+        let InlineKeyboardMarkup { inline_keyboard } = keyboard;
+        assert_eq!(inline_keyboard.len(), 5); // 4 options + End Button
+
+        let button_text = &inline_keyboard[4][0].text;
+        assert!(button_text.contains("🛑"));
+        
+        for row in inline_keyboard {
+            eprintln!("row: {:?}", row);
+            assert_eq!(row.len(), 1);
+            let button_text = &row[0].text;
+            assert!(!button_text.contains("✅"));
+            assert!(!button_text.contains("❌"));
         }
     }
 
     #[test]
     fn test_create_keyboard_with_correct_answer() {
         let question = create_test_question();
-        let keyboard = create_keyboard(&question, Some("Paris"), true, true);
+        let keyboard = create_keyboard(&question, 42, Some("Paris"), true, true);
         
-        if let InlineKeyboardMarkup { inline_keyboard } = keyboard {
-            // Find the button with the selected answer
-            let correct_button = inline_keyboard.iter()
-                .find(|row| row[0].text.contains("Paris"))
-                .unwrap();
-            
-            // Check that correct answer has green checkmark
-            assert!(correct_button[0].text.starts_with("✅"));
-        }
+        let InlineKeyboardMarkup { inline_keyboard } = keyboard;
+        // Find the button with the selected answer
+        let correct_button = inline_keyboard.iter()
+            .find(|row| row[0].text.contains("Paris"))
+            .unwrap();
+        
+        // Check that correct answer has green checkmark
+        assert!(correct_button[0].text.starts_with("✅"));
     }
 
     #[test]
     fn test_create_keyboard_with_incorrect_answer() {
         let question = create_test_question();
-        let keyboard = create_keyboard(&question, Some("London"), true, true);
+        let keyboard = create_keyboard(&question, 42, Some("London"), true, true);
+        
+        let InlineKeyboardMarkup { inline_keyboard } = keyboard;
+        // Find the button with the selected wrong answer
+        let incorrect_button = inline_keyboard.iter()
+            .find(|row| row[0].text.contains("London"))
+            .unwrap();
         
-        if let InlineKeyboardMarkup { inline_keyboard } = keyboard {
-            // Find the button with the selected wrong answer
-            let incorrect_button = inline_keyboard.iter()
-                .find(|row| row[0].text.contains("London"))
-                .unwrap();
-            
-            // Find the button with the correct answer
-            let correct_button = inline_keyboard.iter()
-                .find(|row| row[0].text.contains("Paris"))
-                .unwrap();
-            
-            // Check that wrong answer has red X and correct answer has green checkmark
-            assert!(incorrect_button[0].text.starts_with("❌"));
-            assert!(correct_button[0].text.starts_with("✅"));
+        // Find the button with the correct answer
+        let correct_button = inline_keyboard.iter()
+            .find(|row| row[0].text.contains("Paris"))
+            .unwrap();
+        
+        // Check that wrong answer has red X and correct answer has green checkmark
+        assert!(incorrect_button[0].text.starts_with("❌"));
+        assert!(correct_button[0].text.starts_with("✅"));
+    }
+
+    #[test]
+    fn test_answer_buttons_reference_question_id() {
+        let question = create_test_question();
+        let keyboard = create_keyboard(&question, 42, None, false, true);
+
+        let data = match &keyboard.inline_keyboard[1][0].kind {
+            teloxide::types::InlineKeyboardButtonKind::CallbackData(data) => data.clone(),
+            other => panic!("unexpected button kind: {:?}", other),
+        };
+        assert_eq!(QuizCallbackData::decode(&data), Ok(QuizCallbackData::answer(42, 1, 1)));
+        assert!(question.is_correct_option(0));
+        assert!(!question.is_correct_option(1));
+        assert!(!question.is_correct_option(7));
+    }
+
+    #[test]
+    fn test_callback_data_round_trip() {
+        let payloads = [
+            QuizCallbackData::answer(0, 1, 0),
+            QuizCallbackData::answer(u32::MAX, u32::MAX, 3),
+            QuizCallbackData::end(123456, 757),
+        ];
+
+        for payload in payloads {
+            let encoded = payload.encode();
+            assert!(encoded.len() <= MAX_CALLBACK_DATA_LEN);
+            assert_eq!(QuizCallbackData::decode(&encoded), Ok(payload));
         }
     }
 
+    #[test]
+    fn test_callback_data_rejects_stale_and_malformed() {
+        // Buttons from before the versioned protocol
+        assert!(QuizCallbackData::decode("end_quiz").is_err());
+        assert!(QuizCallbackData::decode("Allahumma aftahli abwaba rahmatik.").is_err());
+        assert!(QuizCallbackData::decode("12:3").is_err());
+
+        assert!(matches!(
+            QuizCallbackData::decode("9:a:1:1:0"),
+            Err(CallbackDataError::UnsupportedVersion(9))
+        ));
+        assert!(QuizCallbackData::decode("1:x:1:1:0").is_err());
+        assert!(QuizCallbackData::decode("1:a:1:1").is_err());
+        assert!(QuizCallbackData::decode("1:a:-1:1:0").is_err());
+        assert!(QuizCallbackData::decode("1:a:1:1:999").is_err());
+    }
+
+    #[test]
+    fn test_deal_questions_does_not_repeat_until_pool_exhausted() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let pool: Vec<u32> = (1..=12).collect();
+        let mut seen = HashSet::new();
+
+        let first = deal_questions(&pool, &mut seen, 5, &mut rng);
+        let second = deal_questions(&pool, &mut seen, 5, &mut rng);
+        let mut dealt: HashSet<u32> = first.iter().chain(second.iter()).copied().collect();
+        assert_eq!(dealt.len(), 10);
+
+        // Only two unseen questions are left, so the pool is recycled for the rest
+        let third = deal_questions(&pool, &mut seen, 5, &mut rng);
+        assert_eq!(third.iter().collect::<HashSet<_>>().len(), 5);
+        let fresh: Vec<_> = third.iter().filter(|id| !dealt.contains(id)).collect();
+        assert_eq!(fresh.len(), 2);
+        dealt.extend(third.iter().copied());
+        assert_eq!(dealt.len(), 12);
+        assert_eq!(seen, third.iter().copied().collect());
+
+        // A quiz longer than the pool never repeats a question within itself
+        let all = deal_questions(&pool, &mut seen, 50, &mut rng);
+        assert_eq!(all.len(), 12);
+        assert_eq!(all.iter().collect::<HashSet<_>>().len(), 12);
+    }
+
+    #[test]
+    fn test_theme_callback_data() {
+        let payload = ThemeCallbackData::new(3);
+        let encoded = payload.encode();
+        assert_eq!(encoded, "1:t:3");
+        assert_eq!(CallbackData::decode(&encoded), Ok(CallbackData::Theme(payload)));
+
+        let quiz = QuizCallbackData::end(5, 6);
+        assert_eq!(CallbackData::decode(&quiz.encode()), Ok(CallbackData::Quiz(quiz)));
+        assert!(CallbackData::decode("1:t:").is_err());
+        assert!(CallbackData::decode("1:t:The Great Masjid").is_err());
+    }
+
+    #[test]
+    fn test_theme_callback_data_fits_longest_category() -> Result<(), Box<dyn Error>> {
+        let questions = load_questions()?;
+        let categories = category_counts(&questions);
+        let longest = (0..categories.len())
+            .max_by_key(|index| categories[*index].0.len())
+            .expect("bundled questions have categories");
+
+        let encoded = ThemeCallbackData::new(longest).encode();
+        assert!(encoded.len() <= MAX_CALLBACK_DATA_LEN);
+        assert_eq!(CallbackData::decode(&encoded), Ok(CallbackData::Theme(ThemeCallbackData::new(longest))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_category_discovery_and_matching() -> Result<(), Box<dyn Error>> {
+        let questions = load_questions()?;
+        let categories = category_counts(&questions);
+        let names: Vec<&str> = categories.iter().map(|(c, _)| c.as_str()).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec!["Hadith", "Hajj", "Solat", "The Great Masjid"]);
+        assert_eq!(categories.iter().map(|(_, n)| n).sum::<usize>(), questions.len());
+
+        assert_eq!(match_category("hadith", &names), Some("Hadith"));
+        assert_eq!(match_category("  HAJJ ", &names), Some("Hajj"));
+        assert_eq!(match_category("great masjid", &names), Some("The Great Masjid"));
+        assert_eq!(match_category("masjid", &names), Some("The Great Masjid"));
+        assert_eq!(match_category("hadeeth", &names), Some("Hadith"));
+        assert_eq!(match_category("solah", &names), Some("Solat"));
+        assert_eq!(match_category("geography", &names), None);
+        assert_eq!(match_category("", &names), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_quiz_args() {
+        let categories = vec![("Hadith".to_string(), 262), ("Hajj".to_string(), 148)];
+        let defaults = QuizSettings::default();
+
+        let settings = parse_quiz_args("10", defaults.clone(), &categories).unwrap();
+        assert_eq!(settings.length, 10);
+        assert_eq!(settings.category, None);
+
+        let settings = parse_quiz_args("hadith 10", defaults.clone(), &categories).unwrap();
+        assert_eq!(settings.length, 10);
+        assert_eq!(settings.category.as_deref(), Some("Hadith"));
+
+        let settings = parse_quiz_args("hard HAJJ", defaults.clone(), &categories).unwrap();
+        assert_eq!(settings.length, DEFAULT_QUIZ_LENGTH);
+        assert_eq!(settings.difficulty, Some(Difficulty::Hard));
+        assert_eq!(settings.category.as_deref(), Some("Hajj"));
+
+        // Lengths are kept within bounds
+        assert_eq!(parse_quiz_args("500", defaults.clone(), &categories).unwrap().length, MAX_QUIZ_LENGTH);
+        assert_eq!(parse_quiz_args("0", defaults.clone(), &categories).unwrap().length, MIN_QUIZ_LENGTH);
+
+        // Arguments apply on top of the saved defaults
+        let saved = QuizSettings {
+            length: 15,
+            category: Some("Hajj".to_string()),
+            difficulty: None,
+            adaptive: false,
+            time_limit_secs: None,
+        };
+        let settings = parse_quiz_args("any", saved, &categories).unwrap();
+        assert_eq!(settings.length, 15);
+        assert_eq!(settings.category, None);
+
+        let settings = parse_quiz_args("adaptive", defaults.clone(), &categories).unwrap();
+        assert!(settings.adaptive);
+
+        let settings = parse_quiz_args("10 30s", defaults.clone(), &categories).unwrap();
+        assert_eq!(settings.time_limit_secs, Some(30));
+        assert_eq!(parse_quiz_args("1s", defaults.clone(), &categories).unwrap().time_limit_secs, Some(MIN_TIME_LIMIT_SECS));
+        assert_eq!(parse_quiz_args("untimed", settings, &categories).unwrap().time_limit_secs, None);
+
+        assert!(parse_quiz_args("geography", defaults, &categories).is_err());
+    }
+
+    #[test]
+    fn test_settings_callback_data_round_trip() {
+        let changes = [
+            SettingsChange::Length(15),
+            SettingsChange::Category(Some(4)),
+            SettingsChange::Category(None),
+            SettingsChange::Difficulty(Some(Difficulty::Easy)),
+            SettingsChange::Difficulty(None),
+            SettingsChange::Adaptive,
+            SettingsChange::TimeLimit(Some(30)),
+            SettingsChange::TimeLimit(None),
+            SettingsChange::Start,
+        ];
+
+        for change in changes {
+            let payload = SettingsCallbackData::new(change);
+            let encoded = payload.encode();
+            assert!(encoded.len() <= MAX_CALLBACK_DATA_LEN);
+            assert_eq!(CallbackData::decode(&encoded), Ok(CallbackData::Settings(payload)));
+        }
+        assert!(CallbackData::decode("1:s:c:The Great Masjid").is_err());
+    }
+
     // Test CSV loading
     #[test]
     fn test_load_questions() -> Result<(), Box<dyn Error>> {
-        let questions = load_questions()?;
+        let csv = create_test_csv()?;
+        let questions = load_questions_from(csv.path())?;
         
-        assert_eq!(questions.len(), 5);
-        assert_eq!(questions[0].question, "What is Rust's ownership model?");
-        assert_eq!(questions[1].question, "Which planet is known as the Red Planet?");
+        assert_eq!(questions.len(), 2);
+        assert_eq!(questions[0].id, 1);
+        assert_eq!(questions[0].question, "What is the capital of France?");
+        assert_eq!(questions[1].id, 2);
+        assert_eq!(questions[1].question, "Which planet is closest to the Sun?");
         
         Ok(())
     }
 
+    #[test]
+    fn test_load_questions_rejects_bad_ids() -> Result<(), Box<dyn Error>> {
+        let mut duplicate = create_test_csv()?;
+        writeln!(duplicate, "2,What is 2+2?,4,3,4,5,6,Math,10")?;
+        assert!(load_questions_from(duplicate.path()).is_err());
+
+        let mut missing = create_test_csv()?;
+        writeln!(missing, ",What is 2+2?,4,3,4,5,6,Math,10")?;
+        assert!(load_questions_from(missing.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_questions_with_difficulty() -> Result<(), Box<dyn Error>> {
+        let mut temp_file = NamedTempFile::new()?;
+        writeln!(temp_file, "id,question,correct_answer,option1,option2,option3,option4,category,points,difficulty")?;
+        writeln!(temp_file, "1,Q1,A,A,B,C,D,Hadith,,easy")?;
+        writeln!(temp_file, "2,Q2,A,A,B,C,D,Hadith,,HARD")?;
+        writeln!(temp_file, "3,Q3,A,A,B,C,D,Hadith,15,hard")?;
+        writeln!(temp_file, "4,Q4,A,A,B,C,D,Hadith,,")?;
+
+        let questions = load_questions_from(temp_file.path())?;
+        let summary: Vec<_> = questions.iter().map(|q| (q.difficulty(), q.points)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Difficulty::Easy, 5),
+                (Difficulty::Hard, 20),
+                (Difficulty::Hard, 15),
+                (Difficulty::Medium, 10),
+            ]
+        );
+
+        let mut unknown = NamedTempFile::new()?;
+        writeln!(unknown, "id,question,correct_answer,option1,option2,option3,option4,category,points,difficulty")?;
+        writeln!(unknown, "1,Q1,A,A,B,C,D,Hadith,,impossible")?;
+        assert!(load_questions_from(unknown.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_adaptive_difficulty() {
+        let answers = |results: &[bool]| results.iter().copied().collect::<VecDeque<bool>>();
+
+        // Not enough answers yet to judge
+        assert_eq!(adjust_difficulty(Difficulty::Medium, &answers(&[true, true])), Difficulty::Medium);
+
+        assert_eq!(adjust_difficulty(Difficulty::Medium, &answers(&[true; 5])), Difficulty::Hard);
+        assert_eq!(adjust_difficulty(Difficulty::Hard, &answers(&[true; 10])), Difficulty::Hard);
+        assert_eq!(adjust_difficulty(Difficulty::Medium, &answers(&[false, false, true, false, true])), Difficulty::Easy);
+        assert_eq!(adjust_difficulty(Difficulty::Easy, &answers(&[false; 6])), Difficulty::Easy);
+        assert_eq!(adjust_difficulty(Difficulty::Medium, &answers(&[true, true, false, true, false, true])), Difficulty::Medium);
+
+        let mut history = QuestionHistory::new(1);
+        for _ in 0..25 {
+            history.record_answer(true);
+        }
+        assert_eq!(history.recent_answers.len(), QuestionHistory::RECENT_ANSWERS_WINDOW);
+    }
+
+    #[test]
+    fn test_speed_bonus() {
+        let limit = Duration::from_secs(30);
+        assert_eq!(speed_bonus(10, Duration::ZERO, limit), 5);
+        assert_eq!(speed_bonus(20, Duration::from_secs(15), limit), 5);
+        assert_eq!(speed_bonus(10, Duration::from_secs(29), limit), 0);
+        assert_eq!(speed_bonus(10, Duration::from_secs(45), limit), 0);
+        assert_eq!(speed_bonus(10, Duration::ZERO, Duration::ZERO), 0);
+    }
+
+    fn timed_question(quiz_id: u32, question_id: u32, secs: u64) -> ActiveQuestion {
+        let mut active = ActiveQuestion::new(
+            quiz_id,
+            question_id,
+            MessageId(10),
+            GameState::InProgress { questions_asked: 1, max_questions: 5 },
+        );
+        active.time_limit = Some(Duration::from_secs(secs));
+        active
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_question_timer_expires_unanswered_question() {
+        let state = Arc::new(create_test_state(vec![create_test_question()]));
+        let active = timed_question(7, 1, 30);
+        state.active_questions.lock().await.insert(100, active.clone());
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let timer = spawn_question_timer(state.clone(), 100, &active, move |expired| async move {
+            let _ = tx.send(expired.question_id);
+        })
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_secs(29)).await;
+        assert!(state.active_questions.lock().await.contains_key(&100));
+        // Answer latency is measured on the same clock
+        let latency = state.active_questions.lock().await[&100].asked_at.elapsed();
+        assert_eq!(speed_bonus(10, latency, Duration::from_secs(30)), 0);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        timer.await.unwrap();
+        assert_eq!(rx.await.unwrap(), 1);
+        assert!(!state.active_questions.lock().await.contains_key(&100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_question_timer_ignores_answered_question() {
+        let state = Arc::new(create_test_state(vec![create_test_question()]));
+        let active = timed_question(7, 1, 30);
+        state.active_questions.lock().await.insert(100, active.clone());
+
+        let expired = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = expired.clone();
+        let timer = spawn_question_timer(state.clone(), 100, &active, move |_| async move {
+            flag.store(true, std::sync::atomic::Ordering::SeqCst);
+        })
+        .unwrap();
+
+        // The question is answered and the quiz moves on before the limit
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        state.active_questions.lock().await.insert(100, timed_question(7, 2, 30));
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        timer.await.unwrap();
+        assert!(!expired.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(state.active_questions.lock().await[&100].question_id, 2);
+
+        // Untimed questions never get a timer
+        let untimed = ActiveQuestion::new(7, 1, MessageId(11), GameState::InProgress { questions_asked: 1, max_questions: 5 });
+        assert!(spawn_question_timer(state.clone(), 100, &untimed, |_| async {}).is_none());
+    }
+
     // Test BotState initialization
     #[test]
     fn test_bot_state_initialization() {
-        let questions = vec![load_questions()][0].as_ref().unwrap().to_vec();
+        let questions = load_questions().unwrap();
         let state = Arc::new(BotState {
             questions,
             active_questions: Mutex::new(HashMap::new()),
             user_scores: Mutex::new(HashMap::new()),
             rng: Mutex::new(StdRng::from_entropy()),
+            reminder_templates: Vec::new(),
+            reminder_templates_act: Vec::new(),
+            user_preferences: Mutex::new(HashMap::new()),
+            question_history: Mutex::new(HashMap::new()),
+            score_events: Mutex::new(Vec::new()),
+            quiz_settings: Mutex::new(HashMap::new()),
+            leaderboard_timezone: chrono_tz::UTC,
+            achievements: Vec::new(),
+            storage: Arc::new(MemoryStorage::default()),
+            dirty: DirtyFlags::default(),
         });
         
-        assert_eq!(state.questions.len(), 5);
+        assert!(!state.questions.is_empty());
+        assert_eq!(state.question(1).map(|q| q.id), Some(1));
     }
 
     // Test score tracking
@@ -158,6 +514,16 @@ mod tests {
             active_questions: Mutex::new(HashMap::new()),
             user_scores: Mutex::new(HashMap::new()),
             rng: Mutex::new(StdRng::from_entropy()),
+            reminder_templates: Vec::new(),
+            reminder_templates_act: Vec::new(),
+            user_preferences: Mutex::new(HashMap::new()),
+            question_history: Mutex::new(HashMap::new()),
+            score_events: Mutex::new(Vec::new()),
+            quiz_settings: Mutex::new(HashMap::new()),
+            leaderboard_timezone: chrono_tz::UTC,
+            achievements: Vec::new(),
+            storage: Arc::new(MemoryStorage::default()),
+            dirty: DirtyFlags::default(),
         });
 
         let user_id = 12345i64;
@@ -170,6 +536,12 @@ mod tests {
             username: username.clone(),
             score: 10,
             last_answer_time: Utc::now(),
+            best_answer_streak: 1,
+            daily_streak: 1,
+            best_daily_streak: 1,
+            last_play_date: Some(Utc::now().date_naive()),
+            categories: HashMap::new(),
+            achievements: Vec::new(),
         });
 
         // Check if score was properly recorded
@@ -178,6 +550,1022 @@ mod tests {
         assert_eq!(user_score.username, username);
     }
 
+    #[test]
+    fn test_group_question_closes_when_everyone_is_wrong() {
+        // The first wrong answer to the first question doesn't close it, even
+        // though the answerer is the only participant so far
+        let mut first = ActiveQuestion::new(1, 1, MessageId(10), GameState::InProgress { questions_asked: 1, max_questions: 5 });
+        first.session.participants.insert(7, QuizParticipant::new("7".to_string()));
+        first.answers.insert(7, 0);
+        assert!(!first.all_expected_players_answered());
+
+        let mut active = ActiveQuestion::new(1, 2, MessageId(11), GameState::InProgress { questions_asked: 2, max_questions: 5 });
+        active.expected_players = [7, 8].into_iter().collect();
+
+        // Stays open while a member from the last question still has to answer,
+        // and a newcomer's answer doesn't close it for them
+        active.answers.insert(7, 0);
+        active.answers.insert(9, 0);
+        assert!(!active.all_expected_players_answered());
+        active.answers.insert(8, 1);
+        assert!(active.all_expected_players_answered());
+    }
+
+    #[test]
+    fn test_group_summary_lists_each_participant() {
+        let mut participants = HashMap::new();
+        let mut alice = QuizParticipant::new("Alice".to_string());
+        alice.record_answer(true, 10);
+        alice.record_answer(false, 10);
+        let mut bilal = QuizParticipant::new("Bilal".to_string());
+        bilal.record_answer(true, 10);
+        bilal.record_answer(true, 10);
+        participants.insert(1, alice);
+        participants.insert(2, bilal);
+
+        let summary = format_group_summary(&participants);
+        assert!(summary.contains("1. Bilal - 2/2 correct, 20 points, best streak 2"));
+        assert!(summary.contains("2. Alice - 1/2 correct, 10 points, best streak 1"));
+    }
+
+    #[test]
+    fn test_quiz_session_results() {
+        let mut session = QuizSession::new(1);
+        session.questions.extend([2, 3, 4]);
+
+        let answers = [(7, 1, true), (7, 2, false), (8, 2, true), (7, 3, true)];
+        for (user_id, question_id, is_correct) in answers {
+            let points = if is_correct { 10 } else { 0 };
+            session
+                .participants
+                .entry(user_id)
+                .or_insert_with(|| QuizParticipant::new(user_id.to_string()))
+                .record_answer(is_correct, points);
+            session.answers.push(SessionAnswer { user_id, question_id, option_index: 0, is_correct, points });
+        }
+
+        assert_eq!(session.correct_count(7), 2);
+        assert_eq!(session.points_earned(7), 20);
+        assert_eq!(session.accuracy(7), 50);
+        assert_eq!(session.missed_questions(Some(7)), vec![2, 4]);
+        assert_eq!(session.chosen_option(7, 2), Some(0));
+        assert_eq!(session.chosen_option(7, 4), None);
+
+        // A user who never answered correctly still gets results
+        assert_eq!(session.correct_count(9), 0);
+        assert_eq!(session.accuracy(9), 0);
+        assert_eq!(session.missed_questions(Some(9)), vec![1, 2, 3, 4]);
+
+        // For a group only questions nobody got right are reviewed
+        assert_eq!(session.missed_questions(None), vec![4]);
+    }
+
+    fn score_event(user_id: i64, points: u32, at: &str) -> ScoreEvent {
+        ScoreEvent {
+            user_id,
+            username: format!("user{}", user_id),
+            chat_id: Some(user_id),
+            question_id: 1,
+            category: None,
+            is_correct: points > 0,
+            points,
+            at: at.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_leaderboard_window_boundaries() {
+        // Wednesday 2024-05-15 23:30 UTC is already Thursday morning in Jakarta
+        let now = "2024-05-15T23:30:00Z".parse().unwrap();
+
+        let start = LeaderboardWindow::Today.start(now, chrono_tz::UTC).unwrap();
+        assert_eq!(start.to_rfc3339(), "2024-05-15T00:00:00+00:00");
+        let start = LeaderboardWindow::Week.start(now, chrono_tz::UTC).unwrap();
+        assert_eq!(start.to_rfc3339(), "2024-05-13T00:00:00+00:00");
+        let start = LeaderboardWindow::Month.start(now, chrono_tz::UTC).unwrap();
+        assert_eq!(start.to_rfc3339(), "2024-05-01T00:00:00+00:00");
+        assert_eq!(LeaderboardWindow::All.start(now, chrono_tz::UTC), None);
+
+        let jakarta = chrono_tz::Asia::Jakarta;
+        let start = LeaderboardWindow::Today.start(now, jakarta).unwrap();
+        assert_eq!(start.to_rfc3339(), "2024-05-15T17:00:00+00:00");
+
+        assert_eq!(LeaderboardWindow::parse("Weekly"), Some(LeaderboardWindow::Week));
+        assert_eq!(LeaderboardWindow::parse(""), Some(LeaderboardWindow::All));
+        assert_eq!(LeaderboardWindow::parse("yearly"), None);
+    }
+
+    #[test]
+    fn test_rank_events_within_window() {
+        let events = vec![
+            score_event(1, 50, "2024-05-01T10:00:00Z"),
+            score_event(2, 10, "2024-05-14T10:00:00Z"),
+            score_event(1, 5, "2024-05-15T10:00:00Z"),
+            score_event(3, 0, "2024-05-15T11:00:00Z"),
+        ];
+
+        let all = rank_events(&events, None, None);
+        assert_eq!(all.iter().map(|e| (e.user_id, e.points)).collect::<Vec<_>>(), vec![(1, 55), (2, 10)]);
+
+        let week = rank_events(&events, Some("2024-05-13T00:00:00Z".parse().unwrap()), None);
+        assert_eq!(week.iter().map(|e| (e.user_id, e.points)).collect::<Vec<_>>(), vec![(2, 10), (1, 5)]);
+    }
+
+    #[test]
+    fn test_group_leaderboard_only_counts_points_earned_in_the_group() {
+        let group = -100;
+        let mut events = vec![
+            score_event(1, 30, "2024-05-15T10:00:00Z"),
+            score_event(1, 10, "2024-05-15T10:00:00Z"),
+            score_event(2, 20, "2024-05-15T10:00:00Z"),
+        ];
+        events[1].chat_id = Some(group);
+        events[2].chat_id = Some(group);
+
+        let standings = rank_events(&events, None, Some(group));
+        assert_eq!(standings.iter().map(|e| (e.user_id, e.points)).collect::<Vec<_>>(), vec![(2, 20), (1, 10)]);
+
+        let global = rank_events(&events, None, None);
+        assert_eq!(global.iter().map(|e| (e.user_id, e.points)).collect::<Vec<_>>(), vec![(1, 40), (2, 20)]);
+
+        // Events logged before chats were recorded only count globally
+        let mut old = score_event(3, 50, "2024-05-15T10:00:00Z");
+        old.chat_id = None;
+        assert!(rank_events(&[old], None, Some(group)).is_empty());
+
+        let parse = |args| parse_leaderboard_args(args, &["Hadith", "The Great Masjid"]).map(|q| (q.window, q.global));
+        assert_eq!(parse(""), Some((LeaderboardWindow::All, false)));
+        assert_eq!(parse("global week"), Some((LeaderboardWindow::Week, true)));
+        assert_eq!(parse("month Global"), Some((LeaderboardWindow::Month, true)));
+        assert_eq!(parse("week month"), None);
+    }
+
+    #[test]
+    fn test_category_leaderboard_args() {
+        let categories = ["Hadith", "The Great Masjid"];
+        let query = parse_leaderboard_args("hadith week", &categories).unwrap();
+        assert_eq!(query.category.as_deref(), Some("Hadith"));
+        assert_eq!(query.window, LeaderboardWindow::Week);
+
+        let query = parse_leaderboard_args("global great masjid", &categories).unwrap();
+        assert_eq!(query.category.as_deref(), Some("The Great Masjid"));
+        assert!(query.global);
+
+        assert_eq!(parse_leaderboard_args("fiqh", &categories), None);
+    }
+
+    #[test]
+    fn test_category_leaderboard_uses_recorded_categories() {
+        let mut first = UserScore::new(1, "first".to_string());
+        first.record_category_answer("Hadith", 1, true, 30);
+        first.record_category_answer("Hajj", 2, true, 50);
+        let mut second = UserScore::new(2, "second".to_string());
+        second.record_category_answer("Hadith", 3, true, 40);
+        let scores = HashMap::from([(1, first), (2, second)]);
+
+        let hadith = rank_category_scores(&scores, "Hadith");
+        assert_eq!(hadith.iter().map(|e| (e.user_id, e.points)).collect::<Vec<_>>(), vec![(2, 40), (1, 30)]);
+        let hajj = rank_category_scores(&scores, "Hajj");
+        assert_eq!(hajj.iter().map(|e| (e.user_id, e.points)).collect::<Vec<_>>(), vec![(1, 50)]);
+
+        // Windowed boards go by the category stored with each answer
+        let mut events = [
+            score_event(1, 10, "2024-05-15T10:00:00Z"),
+            score_event(2, 20, "2024-05-15T10:00:00Z"),
+            score_event(1, 5, "2024-05-15T10:00:00Z"),
+        ];
+        events[0].category = Some("Hadith".to_string());
+        events[1].category = Some("Hajj".to_string());
+        let category = Some("Hadith".to_string());
+        let in_hadith = events.iter().filter(|e| e.category == category);
+        let standings = rank_events(in_hadith, None, None);
+        assert_eq!(standings.iter().map(|e| (e.user_id, e.points)).collect::<Vec<_>>(), vec![(1, 10)]);
+
+        // Events logged before categories were recorded still load
+        let event: ScoreEvent =
+            serde_json::from_str(r#"{"user_id":1,"username":"a","question_id":1,"is_correct":true,"points":10,"at":0}"#).unwrap();
+        assert_eq!(event.category, None);
+    }
+
+    #[test]
+    fn test_category_mastery() {
+        let mut score = UserScore::new(1, "Test".to_string());
+        score.record_category_answer("Hadith", 1, true, 10);
+        score.record_category_answer("Hadith", 1, true, 10);
+        score.record_category_answer("Hadith", 2, false, 0);
+        score.record_category_answer("Hadith", 3, true, 20);
+
+        let hadith = &score.categories["Hadith"];
+        assert_eq!(hadith.answered, 4);
+        assert_eq!(hadith.points, 40);
+        assert_eq!(hadith.accuracy(), 75);
+        // Answering the same question right twice only masters it once
+        assert_eq!(hadith.mastery(4), 50);
+
+        let categories = vec![("Hadith".to_string(), 4), ("Hajj".to_string(), 3)];
+        let text = format_category_mastery(&score, &categories);
+        assert_eq!(text, "Hadith: 50% mastered, 75% accuracy, 40 points\nHajj: not started");
+    }
+
+    #[test]
+    fn test_leaderboard_shows_callers_rank_outside_top() {
+        let events: Vec<_> = (1..=12).map(|user_id| score_event(user_id, 100 - user_id as u32, "2024-05-15T10:00:00Z")).collect();
+        let entries = rank_events(&events, None, None);
+
+        let text = format_leaderboard("All Time", &entries, 12);
+        assert!(text.contains("10. user10 - 90 points"));
+        assert!(!text.contains("user11"));
+        assert!(text.ends_with("12. You - 88 points"));
+
+        let text = format_leaderboard("All Time", &entries, 3);
+        assert!(!text.contains("You"));
+
+        let text = format_leaderboard("All Time", &entries, 99);
+        assert!(text.contains("You haven't scored"));
+    }
+
+    #[test]
+    fn test_parse_score_events_skips_torn_lines() {
+        let line = serde_json::to_string(&score_event(1, 10, "2024-05-15T10:00:00Z")).unwrap();
+        let log = format!("{}\n\n{}\n{{\"user_id\": 2, \"user", line, line);
+        let events: Vec<ScoreEvent> = parse_log(std::path::Path::new("score_events.jsonl"), &log);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_json_storage_round_trip() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let storage = JsonStorage::new(dir.path());
+
+        // Nothing saved yet
+        assert!(storage.load_scores().await?.is_empty());
+        assert!(storage.load_score_events().await?.is_empty());
+
+        let mut scores = HashMap::new();
+        scores.insert(7, UserScore::new(7, "Test".to_string()));
+        storage.save_scores(&scores).await?;
+        assert_eq!(storage.load_scores().await?[&7].username, "Test");
+        // Same format as before, keyed by user id
+        let json = std::fs::read_to_string(dir.path().join("user_scores.json"))?;
+        assert!(json.contains("\"7\""));
+
+        let mut preferences = HashMap::new();
+        preferences.insert(7, UserReminderPreferences::new(7, "Test".to_string()));
+        storage.save_preferences(&preferences).await?;
+        assert_eq!(storage.load_preferences().await?.len(), 1);
+
+        storage.append_score_event(&score_event(7, 10, "2024-05-15T10:00:00Z")).await?;
+        storage.append_score_event(&score_event(8, 20, "2024-05-15T11:00:00Z")).await?;
+        assert_eq!(storage.load_score_events().await?.len(), 2);
+
+        let mut session = QuizSession::new(1);
+        session.participants.insert(7, QuizParticipant::new("Test".to_string()));
+        storage.append_session(&CompletedQuiz { chat_id: 7, finished_at: Utc::now(), session }).await?;
+        let sessions = storage.load_sessions().await?;
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].session.participants.contains_key(&7));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_background_writer_saves_dirty_state() -> Result<(), Box<dyn Error>> {
+        let state = Arc::new(create_test_state(vec![create_test_question()]));
+        let writer = spawn_background_writer(state.clone(), Duration::from_secs(10));
+
+        state.user_scores.lock().await.insert(7, UserScore::new(7, "Test".to_string()));
+        state.record_answer(7, true).await;
+        // Scores haven't been marked dirty yet, so only the history is written
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(state.storage.load_scores().await?.is_empty());
+
+        state.mark_scores_dirty();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(state.storage.load_scores().await?.len(), 1);
+        assert_eq!(state.storage.load_history().await?.len(), 1);
+
+        writer.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_storage_rotates_backups() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let storage = JsonStorage::new(dir.path());
+        let path = dir.path().join("user_scores.json");
+
+        let mut scores = HashMap::new();
+        for score in 0..5 {
+            let mut user_score = UserScore::new(7, "Test".to_string());
+            user_score.score = score;
+            scores.insert(7, user_score);
+            storage.save_scores(&scores).await?;
+        }
+
+        assert!(backup_path(&path, BACKUP_COUNT).exists());
+        assert!(!backup_path(&path, BACKUP_COUNT + 1).exists());
+        let newest_backup: HashMap<i64, UserScore> = serde_json::from_str(&std::fs::read_to_string(backup_path(&path, 1))?)?;
+        assert_eq!(newest_backup[&7].score, 3);
+
+        // No temporary files are left behind
+        let leftovers = std::fs::read_dir(dir.path())?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        // One left by a process that crashed mid-save is cleared on load, even
+        // with the same pid as this one, as after a container restart
+        let stale = dir.path().join(format!(".user_scores.json.{}.0.tmp", std::process::id()));
+        std::fs::write(&stale, "{")?;
+        assert_eq!(storage.load_scores().await?[&7].score, 4);
+        assert!(!stale.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_storage_recovers_from_corrupt_file() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let storage = JsonStorage::new(dir.path());
+        let path = dir.path().join("user_scores.json");
+
+        // An empty file, as left by a crash mid-write, loads as no scores
+        std::fs::write(&path, "")?;
+        assert!(storage.load_scores().await?.is_empty());
+
+        let mut scores = HashMap::new();
+        scores.insert(7, UserScore::new(7, "Test".to_string()));
+        storage.save_scores(&scores).await?;
+        storage.save_scores(&scores).await?;
+
+        // A truncated file falls back to the latest readable backup
+        std::fs::write(&path, "{\"7\": {\"user_id\": 7,")?;
+        assert_eq!(storage.load_scores().await?[&7].username, "Test");
+
+        // With every copy damaged it starts empty but keeps the damaged file
+        for n in 1..=BACKUP_COUNT {
+            let _ = std::fs::remove_file(backup_path(&path, n));
+        }
+        assert!(storage.load_scores().await?.is_empty());
+        assert!(dir.path().join("user_scores.json.corrupt").exists());
+
+        // A file that can't be read at all also falls back to a backup
+        storage.save_scores(&scores).await?;
+        storage.save_scores(&scores).await?;
+        std::fs::remove_file(&path)?;
+        std::fs::create_dir(&path)?;
+        assert_eq!(storage.load_scores().await?[&7].username, "Test");
+
+        // And is an error rather than an empty start when no backup is left
+        for n in 1..=BACKUP_COUNT {
+            let _ = std::fs::remove_file(backup_path(&path, n));
+        }
+        assert!(storage.load_scores().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_storage_concurrent_saves() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let storage = Arc::new(JsonStorage::new(dir.path()));
+
+        let saves = (0..8).map(|user_id| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                let mut preferences = HashMap::new();
+                preferences.insert(user_id, UserReminderPreferences::new(user_id, "Test".to_string()));
+                storage.save_preferences(&preferences).await
+            })
+        });
+        for save in saves.collect::<Vec<_>>() {
+            save.await??;
+        }
+
+        assert_eq!(storage.load_preferences().await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage_round_trip() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("trivia.db");
+        let storage = SqliteStorage::open(&path)?;
+
+        let mut scores = HashMap::new();
+        let mut score = UserScore::new(7, "Test".to_string());
+        score.score = 30;
+        score.record_category_answer("Hadith", 1, true, 30);
+        scores.insert(7, score);
+        scores.insert(8, UserScore::new(8, "Other".to_string()));
+        storage.save_scores(&scores).await?;
+
+        scores.get_mut(&7).unwrap().score = 40;
+        storage.save_scores(&scores).await?;
+
+        let mut history = HashMap::new();
+        history.insert(7, QuestionHistory::new(7));
+        storage.save_history(&history).await?;
+
+        storage.append_score_event(&score_event(7, 10, "2024-05-15T10:00:00Z")).await?;
+        storage
+            .append_session(&CompletedQuiz { chat_id: -100, finished_at: Utc::now(), session: QuizSession::new(1) })
+            .await?;
+        drop(storage);
+
+        // Reopening runs no migrations and sees everything saved
+        let storage = SqliteStorage::open(&path)?;
+        let loaded = storage.load_scores().await?;
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[&7].score, 40);
+        assert_eq!(loaded[&7].categories["Hadith"].correct, 1);
+        assert_eq!(storage.load_history().await?.len(), 1);
+
+        let events = storage.load_score_events().await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].chat_id, Some(7));
+        assert_eq!(events[0].at.to_rfc3339(), "2024-05-15T10:00:00+00:00");
+        assert_eq!(storage.load_sessions().await?[0].chat_id, -100);
+
+        // Users missing from the map are removed
+        scores.remove(&8);
+        storage.save_scores(&scores).await?;
+        assert_eq!(storage.load_scores().await?.len(), 1);
+
+        // A group's reminder preferences are kept without adding it as a user
+        let mut preferences = HashMap::new();
+        preferences.insert(-100, UserReminderPreferences::new(-100, "Study circle".to_string()));
+        storage.save_preferences(&preferences).await?;
+        assert_eq!(storage.load_preferences().await?[&-100].username, "Study circle");
+        drop(storage);
+        let conn = rusqlite::Connection::open(&path)?;
+        let users: Vec<i64> = conn
+            .prepare("SELECT user_id FROM users")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        assert!(!users.contains(&-100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_json_into_sqlite() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let json = JsonStorage::new(dir.path());
+        let mut scores = HashMap::new();
+        scores.insert(7, UserScore::new(7, "Test".to_string()));
+        json.save_scores(&scores).await?;
+        let mut preferences = HashMap::new();
+        preferences.insert(7, UserReminderPreferences::new(7, "Test".to_string()));
+        preferences.insert(9, UserReminderPreferences::new(9, "Reader".to_string()));
+        json.save_preferences(&preferences).await?;
+        json.append_score_event(&score_event(7, 10, "2024-05-15T10:00:00Z")).await?;
+
+        let path = dir.path().join("trivia.db");
+        let sqlite = SqliteStorage::open(&path)?;
+        assert!(sqlite.needs_import().await?);
+        let summary = sqlite.import_from(&json).await?;
+        assert_eq!(
+            summary,
+            ImportSummary { scores: 1, preferences: 2, histories: 0, quiz_settings: 0, score_events: 1, sessions: 0 }
+        );
+        assert_eq!(sqlite.load_preferences().await?[&9].username, "Reader");
+        assert_eq!(sqlite.load_scores().await?[&7].username, "Test");
+        assert_eq!(sqlite.load_score_events().await?.len(), 1);
+        drop(sqlite);
+
+        // The finished import is remembered, so a restart doesn't import twice
+        let sqlite = SqliteStorage::open(&path)?;
+        assert!(!sqlite.needs_import().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bot_state_saves_through_storage() -> Result<(), Box<dyn Error>> {
+        let state = create_test_state(vec![create_test_question()]);
+        state.user_scores.lock().await.insert(7, UserScore::new(7, "Test".to_string()));
+        state.save_scores().await?;
+        state.set_quiz_settings(7, QuizSettings::default().with_length(10)).await;
+        // Quiz settings are only marked for saving until the next flush
+        assert!(state.storage.load_quiz_settings().await?.is_empty());
+        state.flush().await?;
+
+        assert_eq!(state.storage.load_scores().await?.len(), 1);
+        assert_eq!(state.storage.load_quiz_settings().await?[&7].length, 10);
+        // Choosing quiz settings doesn't sign anyone up for reminders
+        assert!(state.storage.load_preferences().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_active_quizzes_survive_restart() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let storage: Arc<dyn Storage> = Arc::new(SqliteStorage::open(dir.path().join("trivia.db"))?);
+        let mut state = create_test_state(vec![create_test_question()]);
+        state.storage = storage.clone();
+
+        let mut active = ActiveQuestion::new(3, 1, MessageId(10), GameState::InProgress { questions_asked: 1, max_questions: 2 });
+        active.time_limit = Some(Duration::from_secs(30));
+        active.adaptive = true;
+        active.remaining_questions = vec![1];
+        active.answers.insert(7, 0);
+        state.active_questions.lock().await.insert(-100, active);
+        state.mark_quizzes_dirty();
+        state.flush().await?;
+
+        let saved = storage.load_active_quizzes().await?;
+        assert_eq!(saved[&-100].quiz_id, 3);
+        assert_eq!(saved[&-100].time_limit_secs, Some(30));
+
+        // A fresh process picks the quiz up where it left off
+        let restarted = create_test_state(vec![create_test_question()]);
+        assert_eq!(restarted.restore_active_quizzes(saved.clone(), Utc::now()).await, 1);
+        let restored = restarted.take_active_question(-100, 3, 1).await.expect("quiz restored");
+        assert_eq!(restored.remaining_questions, vec![1]);
+        assert_eq!(restored.answers[&7], 0);
+        assert_eq!(restored.time_limit, Some(Duration::from_secs(30)));
+        assert!(restored.adaptive);
+
+        // Stale quizzes and ones asking removed questions are dropped
+        let later = create_test_state(vec![create_test_question()]);
+        assert_eq!(later.restore_active_quizzes(saved.clone(), Utc::now() + chrono::Duration::days(2)).await, 0);
+        let mut unknown = saved;
+        unknown.get_mut(&-100).unwrap().remaining_questions.push(99);
+        assert_eq!(later.restore_active_quizzes(unknown, Utc::now()).await, 0);
+
+        // Finished quizzes are removed from storage on the next flush
+        state.active_questions.lock().await.clear();
+        state.mark_quizzes_dirty();
+        state.flush().await?;
+        assert!(storage.load_active_quizzes().await?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_templates_reports_bad_rows() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let csv = "message,act,reference\n\
+                   Smile,Smile at your brother,Tirmidhi\n\
+                   ,Give charity,Muslim\n\
+                   \"Unclosed,Visit the sick,Bukhari\n";
+        let (acts, errors) = parse_templates::<ReminderTemplateAct>(csv)?;
+        assert_eq!(acts.len(), 1);
+        assert_eq!(errors[0], TemplateRowError { line: 3, message: "missing message".to_string() });
+        assert_eq!(errors.len(), 2);
+
+        assert!(parse_templates::<ReminderTemplateAct>("<!DOCTYPE html><html></html>").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_template_source_falls_back_to_local_file() -> Result<(), Box<dyn Error>> {
+        let bundled = TemplateSource::reminders().with_overrides(None, Some(String::new()));
+        assert_eq!(bundled.url, None);
+        assert!(!load_template_source::<ReminderTemplate>(&bundled).await.is_empty());
+
+        // The bundled acts are there too
+        let acts = TemplateSource::reminder_acts().with_overrides(None, Some(String::new()));
+        assert!(!load_template_source::<ReminderTemplateAct>(&acts).await.is_empty());
+
+        // A cached copy of the sheet is preferred to the bundled file, and sits beside it
+        let dir = tempfile::tempdir()?;
+        let bundled_path = dir.path().join("acts.csv");
+        std::fs::write(&bundled_path, "message,act,reference\nSmile,Bundled smile,Tirmidhi")?;
+        let offline = TemplateSource::reminder_acts().with_overrides(
+            Some(bundled_path.display().to_string()),
+            Some("http://127.0.0.1:9/reminders.csv".to_string()),
+        );
+        assert_eq!(offline.cache_path(), Some(dir.path().join("acts.cache.csv")));
+        assert_eq!(load_template_source::<ReminderTemplateAct>(&offline).await[0].act, "Bundled smile");
+        std::fs::write(dir.path().join("acts.cache.csv"), "message,act,reference\nSmile,Cached smile,Tirmidhi")?;
+        assert_eq!(load_template_source::<ReminderTemplateAct>(&offline).await[0].act, "Cached smile");
+
+        // Nothing to load from means no templates rather than an error
+        let missing = TemplateSource::reminder_acts().with_overrides(Some("missing.csv".to_string()), Some(String::new()));
+        assert!(load_template_source::<ReminderTemplateAct>(&missing).await.is_empty());
+        Ok(())
+    }
+
+    // Checks the parts of MarkdownV2 that reminders use: every special character
+    // outside a code span is escaped, apart from paired `*` for bold, and code
+    // spans are closed
+    fn assert_valid_markdown_v2(text: &str) {
+        let mut chars = text.chars();
+        let (mut in_code, mut bold_markers) = (false, 0);
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => assert!(chars.next().is_some(), "dangling escape in {:?}", text),
+                '`' => in_code = !in_code,
+                '*' if !in_code => bold_markers += 1,
+                '_' | '[' | ']' | '(' | ')' | '~' | '>' | '#' | '+' | '-' | '=' | '|' | '{' | '}' | '.' | '!' if !in_code => {
+                    panic!("unescaped {:?} in {:?}", c, text)
+                }
+                _ => {}
+            }
+        }
+        assert!(!in_code, "unclosed code span in {:?}", text);
+        assert_eq!(bold_markers % 2, 0, "unpaired bold marker in {:?}", text);
+    }
+
+    #[test]
+    fn test_bundled_reminders_format_as_markdown_v2() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (duas, errors) = parse_templates::<ReminderTemplate>(&std::fs::read_to_string("reminders.csv")?)?;
+        assert!(errors.is_empty() && !duas.is_empty());
+        for dua in &duas {
+            assert_valid_markdown_v2(&format_dua_reminder(dua));
+        }
+
+        let (acts, errors) = parse_templates::<ReminderTemplateAct>(&std::fs::read_to_string("reminder_acts.csv")?)?;
+        assert!(errors.is_empty() && !acts.is_empty());
+        for act in &acts {
+            assert_valid_markdown_v2(&format_act_reminder(act));
+        }
+
+        // A backtick can't end the code span early
+        let act = ReminderTemplateAct {
+            message: "Pray two rak`ahs".to_string(),
+            act: "Pray two rak`ahs before sitting.".to_string(),
+            reference: "Sahih al-Bukhari 444".to_string(),
+        };
+        assert!(format_act_reminder(&act).contains("`Pray two rak\\`ahs before sitting.`"));
+        assert_valid_markdown_v2(&format_act_reminder(&act));
+        Ok(())
+    }
+
+    struct FixedClock(chrono::DateTime<Utc>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> chrono::DateTime<Utc> {
+            self.0
+        }
+    }
+
+    fn scheduler_at(now: &str, catch_up: CatchUp) -> Scheduler {
+        Scheduler::new(Arc::new(FixedClock(now.parse().unwrap())), catch_up)
+    }
+
+    fn due_streams(prefs: &mut UserReminderPreferences, now: &str) -> Vec<ReminderStream> {
+        prefs.take_due(&scheduler_at(now, CatchUp::Skip)).into_iter().map(|(_, stream)| stream).collect()
+    }
+
+    #[test]
+    fn test_reminder_schedule_next_due() {
+        let at = |h, m| chrono::NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let utc = |s: &str| s.parse::<chrono::DateTime<Utc>>().unwrap();
+        let mut schedule = ReminderSchedule {
+            timezone: "Asia/Jakarta".parse().unwrap(),
+            delivery_times: vec![at(6, 0), at(21, 0), at(23, 0)],
+            quiet_hours: None,
+            cron: None,
+        };
+
+        // 06:00 in Jakarta (UTC+7) is 23:00 UTC the day before
+        assert_eq!(schedule.next_due(utc("2024-05-15T20:00:00Z")), Some(utc("2024-05-15T23:00:00Z")));
+        assert_eq!(schedule.next_due(utc("2024-05-15T23:00:00Z")), Some(utc("2024-05-16T14:00:00Z")));
+
+        // Quiet hours wrap past midnight and hold back the 23:00 reminder
+        schedule.quiet_hours = Some(QuietHours { start: at(22, 0), end: at(5, 0) });
+        assert_eq!(schedule.next_due(utc("2024-05-16T14:00:00Z")), Some(utc("2024-05-16T23:00:00Z")));
+        schedule.quiet_hours = Some(QuietHours { start: at(0, 0), end: at(23, 59) });
+        assert_eq!(schedule.next_due(utc("2024-05-16T14:00:00Z")), None);
+
+        // A new opt-in waits for its first delivery time
+        let mut prefs = UserReminderPreferences::new(7, "Test".to_string());
+        prefs.opted_in = true;
+        assert!(prefs.take_due(&scheduler_at("2024-05-15T07:00:00Z", CatchUp::Skip)).is_empty());
+        assert_eq!(prefs.next_reminder, Some(utc("2024-05-15T12:00:00Z")));
+        assert!(!prefs.take_due(&scheduler_at("2024-05-15T12:00:30Z", CatchUp::Skip)).is_empty());
+        assert_eq!(prefs.next_reminder, Some(utc("2024-05-15T18:00:00Z")));
+    }
+
+    #[test]
+    fn test_reminder_subscriptions() {
+        let utc = |s: &str| s.parse::<chrono::DateTime<Utc>>().unwrap();
+        let mut prefs = UserReminderPreferences::new(7, "Test".to_string());
+        prefs.opted_in = true;
+        prefs.subscriptions.duas.frequency = ReminderFrequency::Daily;
+        prefs.subscriptions.sunnah_acts.enabled = false;
+
+        let mut sent = Vec::new();
+        for at in ["2024-05-15T12:00:00Z", "2024-05-15T18:00:00Z", "2024-05-16T06:00:00Z"] {
+            prefs.next_reminder = Some(utc(at));
+            sent.push(due_streams(&mut prefs, at));
+        }
+        assert_eq!(sent, vec![vec![ReminderStream::Duas], vec![], vec![ReminderStream::Duas]]);
+
+        // Weekly waits for the next Monday-started week
+        prefs.subscriptions.duas.frequency = ReminderFrequency::Weekly;
+        prefs.subscriptions.sunnah_acts.enabled = true;
+        prefs.next_reminder = Some(utc("2024-05-19T06:00:00Z"));
+        assert_eq!(due_streams(&mut prefs, "2024-05-19T06:00:00Z"), vec![ReminderStream::SunnahActs]);
+        prefs.next_reminder = Some(utc("2024-05-20T06:00:00Z"));
+        assert_eq!(due_streams(&mut prefs, "2024-05-20T06:00:00Z"), ReminderStream::ALL.to_vec());
+
+        // Preferences saved before subscriptions existed get both streams
+        let old: UserReminderPreferences = serde_json::from_str(
+            r#"{"user_id": 7, "username": "Test", "opted_in": true, "last_reminder": null}"#,
+        ).unwrap();
+        assert_eq!(old.subscriptions, ReminderSubscriptions::default());
+        assert!(old.subscriptions.duas.enabled && old.subscriptions.sunnah_acts.enabled);
+    }
+
+    #[test]
+    fn test_opt_in_text_describes_the_users_schedule() {
+        let at = |h, m| chrono::NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let mut prefs = UserReminderPreferences::new(7, "Test".to_string());
+        prefs.opted_in = true;
+        prefs.schedule.delivery_times = vec![at(7, 30), at(20, 0)];
+        prefs.subscriptions.duas.frequency = ReminderFrequency::Daily;
+        prefs.subscriptions.sunnah_acts.enabled = false;
+
+        let text = opt_in_text(&prefs);
+        assert!(text.contains("Delivery times: 07:30, 20:00"));
+        assert!(text.contains("Duas: Daily"));
+        assert!(text.contains("Sunnah acts: Off"));
+        assert!(!text.contains("4times"));
+    }
+
+    #[test]
+    fn test_reminder_rotation_is_per_user() {
+        let utc = |s: &str| s.parse::<chrono::DateTime<Utc>>().unwrap();
+        let mut prefs = UserReminderPreferences::new(7, "Test".to_string());
+
+        // A dua stays for the week, an act for the day (Wednesday to the next Tuesday)
+        let days = ["2024-05-15", "2024-05-16", "2024-05-19", "2024-05-20", "2024-05-21"];
+        let positions: Vec<(Option<usize>, Option<usize>)> = days
+            .iter()
+            .map(|day| {
+                let now = utc(&format!("{}T12:00:00Z", day));
+                (
+                    prefs.template_position(ReminderStream::Duas, now, 2),
+                    prefs.template_position(ReminderStream::SunnahActs, now, 3),
+                )
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            vec![(Some(0), Some(0)), (Some(0), Some(1)), (Some(0), Some(2)), (Some(1), Some(0)), (Some(1), Some(1))]
+        );
+        assert_eq!(prefs.template_position(ReminderStream::Duas, utc("2024-05-27T12:00:00Z"), 2), Some(0));
+        assert_eq!(prefs.template_position(ReminderStream::Duas, utc("2024-05-27T12:00:00Z"), 0), None);
+
+        // Opting in again starts each series over
+        prefs.subscriptions.restart_series();
+        assert_eq!(prefs.template_position(ReminderStream::SunnahActs, utc("2024-05-28T12:00:00Z"), 3), Some(0));
+    }
+
+    #[test]
+    fn test_subscription_callback_data() {
+        for stream in ReminderStream::ALL {
+            let mut changes = vec![SubscriptionChange::Toggle(stream)];
+            changes.extend(ReminderFrequency::ALL.iter().map(|&f| SubscriptionChange::Frequency(stream, f)));
+            for change in changes {
+                let payload = SubscriptionCallbackData::new(change);
+                assert_eq!(CallbackData::decode(&payload.encode()), Ok(CallbackData::Subscription(payload)));
+            }
+        }
+        assert!(SubscriptionCallbackData::decode("1:r:x:on").is_err());
+        assert!(SubscriptionCallbackData::decode("1:r:d:monthly").is_err());
+
+        let mut subscriptions = ReminderSubscriptions::default();
+        subscriptions.sunnah_acts.enabled = false;
+        // A frequency row only for the stream that is on
+        assert_eq!(create_subscriptions_keyboard(&subscriptions).inline_keyboard.len(), 3);
+    }
+
+    #[test]
+    fn test_cron_rule() {
+        let utc = |s: &str| s.parse::<chrono::DateTime<Utc>>().unwrap();
+        let london: chrono_tz::Tz = "Europe/London".parse().unwrap();
+
+        // Weekdays at 06:00 and 18:00; 2024-05-17 is a Friday, in BST (UTC+1)
+        let weekdays = CronRule::parse("0 6,18 * * 1-5").unwrap();
+        assert_eq!(weekdays.next_after(utc("2024-05-17T12:00:00Z"), london), Some(utc("2024-05-17T17:00:00Z")));
+        assert_eq!(weekdays.next_after(utc("2024-05-17T17:00:00Z"), london), Some(utc("2024-05-20T05:00:00Z")));
+
+        let every_quarter = CronRule::parse("*/15 9 * * *").unwrap();
+        assert_eq!(every_quarter.next_after(utc("2024-05-17T08:20:00Z"), chrono_tz::UTC), Some(utc("2024-05-17T09:00:00Z")));
+        assert_eq!(every_quarter.next_after(utc("2024-05-17T09:45:00Z"), chrono_tz::UTC), Some(utc("2024-05-18T09:00:00Z")));
+
+        // Day of month or weekday when both are given, and Sunday as 7
+        let first_or_sunday = CronRule::parse("0 12 1 * 7").unwrap();
+        assert_eq!(first_or_sunday.next_after(utc("2024-05-17T00:00:00Z"), chrono_tz::UTC), Some(utc("2024-05-19T12:00:00Z")));
+        assert_eq!(first_or_sunday.next_after(utc("2024-05-26T12:00:00Z"), chrono_tz::UTC), Some(utc("2024-06-01T12:00:00Z")));
+
+        for bad in ["", "0 6 * *", "60 * * * *", "0 24 * * *", "0 6 * * 8", "*/0 * * * *", "5-1 * * * *"] {
+            assert!(CronRule::parse(bad).is_err(), "{:?} should be rejected", bad);
+        }
+
+        assert_eq!(weekdays.min_interval(), chrono::Duration::hours(12));
+        assert_eq!(every_quarter.min_interval(), chrono::Duration::minutes(15));
+        assert_eq!(CronRule::parse("30 23,1 * * *").unwrap().min_interval(), chrono::Duration::hours(2));
+
+        // Saved as its expression
+        let json = serde_json::to_string(&weekdays).unwrap();
+        assert_eq!(json, r#""0 6,18 * * 1-5""#);
+        assert_eq!(serde_json::from_str::<CronRule>(&json).unwrap(), weekdays);
+    }
+
+    #[test]
+    fn test_scheduler_catch_up() {
+        let utc = |s: &str| s.parse::<chrono::DateTime<Utc>>().unwrap();
+        // Every six hours, down from 00:00 until just after 12:05
+        let next_after = |t: chrono::DateTime<Utc>| Some(t + chrono::Duration::hours(6));
+        let next_run = utc("2024-05-15T00:00:00Z");
+        let run = |catch_up| scheduler_at("2024-05-15T12:05:00Z", catch_up).due_runs(next_run, next_after);
+
+        let skip = run(CatchUp::Skip);
+        assert_eq!(skip.runs, vec![utc("2024-05-15T12:00:00Z")]);
+        assert_eq!(skip.next_run, Some(utc("2024-05-15T18:00:00Z")));
+        assert_eq!(run(CatchUp::Once).runs, vec![utc("2024-05-15T12:00:00Z")]);
+        assert_eq!(run(CatchUp::All).runs.len(), 3);
+
+        // Longer than the grace period, skip drops everything
+        let late = scheduler_at("2024-05-15T13:00:00Z", CatchUp::Skip).due_runs(next_run, next_after);
+        assert!(late.runs.is_empty());
+        assert_eq!(late.next_run, Some(utc("2024-05-15T18:00:00Z")));
+
+        // After a long downtime, all only makes up the latest runs
+        let all = scheduler_at("2024-06-15T00:00:00Z", CatchUp::All).due_runs(next_run, next_after);
+        assert_eq!(all.runs.len(), MAX_CATCH_UP_RUNS);
+        assert_eq!(all.runs.last(), Some(&utc("2024-06-15T00:00:00Z")));
+
+        // Catching up a reminder stream sends one reminder per missed delivery
+        let mut prefs = UserReminderPreferences::new(7, "Test".to_string());
+        prefs.opted_in = true;
+        prefs.schedule.delivery_times = vec![chrono::NaiveTime::from_hms_opt(8, 0, 0).unwrap()];
+        prefs.next_reminder = Some(utc("2024-05-15T08:00:00Z"));
+        let due = prefs.take_due(&scheduler_at("2024-05-17T09:00:00Z", CatchUp::All));
+        let acts: Vec<_> = due.iter().filter(|(_, stream)| *stream == ReminderStream::SunnahActs).map(|(run, _)| *run).collect();
+        assert_eq!(acts, vec![utc("2024-05-15T08:00:00Z"), utc("2024-05-16T08:00:00Z"), utc("2024-05-17T08:00:00Z")]);
+        assert_eq!(prefs.next_reminder, Some(utc("2024-05-18T08:00:00Z")));
+    }
+
+    #[test]
+    fn test_parse_schedule_args() {
+        let at = |h, m| chrono::NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert_eq!(parse_schedule_args(""), Ok(None));
+        assert_eq!(
+            parse_schedule_args("tz Africa/Lagos"),
+            Ok(Some(ScheduleChange::Timezone(chrono_tz::Africa::Lagos)))
+        );
+        assert_eq!(
+            parse_schedule_args("times evening Early 06:00"),
+            Ok(Some(ScheduleChange::DeliveryTimes(vec![at(6, 0), at(19, 0)])))
+        );
+        assert_eq!(
+            parse_schedule_args("quiet 22:00-07:00"),
+            Ok(Some(ScheduleChange::QuietHours(Some(QuietHours { start: at(22, 0), end: at(7, 0) }))))
+        );
+        assert_eq!(parse_schedule_args("quiet off"), Ok(Some(ScheduleChange::QuietHours(None))));
+        assert!(parse_schedule_args("tz Mars/Olympus").is_err());
+        assert!(parse_schedule_args("times 25:00").is_err());
+        // Delivery times get the same spacing as cron rules, across midnight too
+        assert!(parse_schedule_args("times 08:00 08:30").is_err());
+        assert!(parse_schedule_args("times 23:45 00:30").is_err());
+        assert!(parse_schedule_args("times 08:00 09:00 23:00").is_ok());
+        assert!(parse_schedule_args("quiet 22:00").is_err());
+        assert_eq!(
+            parse_schedule_args("cron 0 6,18 * * 1-5"),
+            Ok(Some(ScheduleChange::Cron(Some(CronRule::parse("0 6,18 * * 1-5").unwrap()))))
+        );
+        assert!(parse_schedule_args("cron 0 6").is_err());
+        // Rules that would send more than once an hour are refused
+        assert!(parse_schedule_args("cron * * * * *").is_err());
+        assert!(parse_schedule_args("cron 0,30 9 * * *").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket_limits_rate() {
+        let mut bucket = TokenBucket::new(2.0, 4.0);
+        let start = tokio::time::Instant::now();
+        for _ in 0..10 {
+            bucket.acquire().await;
+        }
+        // A burst of two, then eight more at four a second
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delivery_queue_retries_and_reports() {
+        use teloxide::types::ChatId;
+        use teloxide::{ApiError, RequestError};
+
+        let io_error = || RequestError::Io(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset"));
+        let responses: std::sync::Mutex<HashMap<i64, VecDeque<Result<(), RequestError>>>> = std::sync::Mutex::new(HashMap::from([
+            (1, VecDeque::from([Ok(())])),
+            (2, VecDeque::from([Err(RequestError::RetryAfter(Duration::from_secs(5))), Ok(())])),
+            (3, VecDeque::from([Err(io_error()), Err(io_error()), Err(io_error())])),
+            (4, VecDeque::from([Err(RequestError::Api(ApiError::BotBlocked))])),
+            (5, VecDeque::from([Err(io_error()), Ok(())])),
+        ]));
+        let attempts = std::sync::Mutex::new(Vec::new());
+
+        let mut queue = DeliveryQueue::new(TokenBucket::new(10.0, 10.0), 3);
+        let messages = (1..=5).map(|id| (ChatId(id), format!("Reminder {}", id))).collect();
+        let start = tokio::time::Instant::now();
+        let report = queue
+            .broadcast(messages, |chat_id, _text| {
+                attempts.lock().unwrap().push(chat_id.0);
+                let response = responses.lock().unwrap().get_mut(&chat_id.0).unwrap().pop_front().unwrap();
+                async move { response }
+            })
+            .await;
+
+        assert_eq!(report.sent, 3);
+        assert_eq!(report.retried, 2);
+        assert_eq!(report.failed, vec![ChatId(3)]);
+        assert_eq!(report.blocked, vec![ChatId(4)]);
+        // Blocked users aren't retried, failing ones stop after the attempt limit
+        assert_eq!(attempts.lock().unwrap().as_slice(), &[1, 2, 2, 3, 3, 3, 4, 5, 5]);
+        // The flood wait plus 1s and 2s of backoff for user 3 and 1s for user 5
+        assert!(start.elapsed() >= Duration::from_secs(9));
+    }
+
+    #[test]
+    fn test_load_achievements() -> Result<(), Box<dyn Error>> {
+        let achievements = load_achievements()?;
+        assert!(achievements.iter().any(|a| a.rule == AchievementRule::CorrectAnswers { count: 1 }));
+
+        let mut temp_file = NamedTempFile::new()?;
+        write!(
+            temp_file,
+            r#"[{{"id": "a", "name": "A", "description": "", "badge": "", "rule": {{"type": "points", "points": 1}}}},
+                {{"id": "a", "name": "B", "description": "", "badge": "", "rule": {{"type": "points", "points": 2}}}}]"#
+        )?;
+        assert!(load_achievements_from(temp_file.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_achievements() {
+        let achievement = |id: &str, rule| Achievement {
+            id: id.to_string(),
+            name: id.to_string(),
+            description: String::new(),
+            badge: String::new(),
+            rule,
+        };
+        let achievements = vec![
+            achievement("first", AchievementRule::CorrectAnswers { count: 1 }),
+            achievement("hadith", AchievementRule::CategoryAnswered { category: "Hadith".to_string(), count: 2 }),
+            achievement("perfect", AchievementRule::PerfectQuiz { min_questions: 2 }),
+        ];
+
+        let mut score = UserScore::new(7, "Test".to_string());
+        score.record_category_answer("Hadith", 1, true, 10);
+        let unlocked = unlock_achievements(&achievements, &mut score, None, Utc::now());
+        assert_eq!(unlocked.iter().map(|a| a.id.as_str()).collect::<Vec<_>>(), vec!["first"]);
+
+        // Already unlocked achievements aren't announced again
+        score.record_category_answer("Hadith", 2, true, 10);
+        let mut session = QuizSession::new(1);
+        session.questions.push(2);
+        let mut participant = QuizParticipant::new("Test".to_string());
+        participant.record_answer(true, 10);
+        participant.record_answer(true, 10);
+        session.participants.insert(7, participant);
+        let unlocked = unlock_achievements(&achievements, &mut score, Some(&session), Utc::now());
+        assert_eq!(unlocked.iter().map(|a| a.id.as_str()).collect::<Vec<_>>(), vec!["hadith", "perfect"]);
+        assert!(unlock_achievements(&achievements, &mut score, Some(&session), Utc::now()).is_empty());
+
+        let badges = format_badges(&achievements, Some(&score));
+        assert!(!badges.contains("🔒"));
+        assert!(format_badges(&achievements, None).contains("None yet"));
+    }
+
+    #[test]
+    fn test_streak_multiplier() {
+        assert_eq!(apply_streak_multiplier(10, 1), 10);
+        assert_eq!(apply_streak_multiplier(10, 2), 10);
+        assert_eq!(apply_streak_multiplier(10, 3), 15);
+        assert_eq!(apply_streak_multiplier(10, 4), 15);
+        assert_eq!(apply_streak_multiplier(10, 5), 20);
+        assert_eq!(apply_streak_multiplier(10, 12), 20);
+
+        let mut participant = QuizParticipant::new("Alice".to_string());
+        for is_correct in [true, true, true, false, true] {
+            participant.record_answer(is_correct, 10);
+        }
+        assert_eq!(participant.streak, 1);
+        assert_eq!(participant.best_streak, 3);
+    }
+
+    #[test]
+    fn test_daily_streak() {
+        let day = |d: u32| NaiveDate::from_ymd_opt(2025, 1, d).unwrap();
+        let mut score = UserScore::new(1, "Alice".to_string());
+
+        score.record_play(day(1));
+        score.record_play(day(1));
+        score.record_play(day(2));
+        score.record_play(day(3));
+        assert_eq!(score.daily_streak, 3);
+        assert_eq!(score.current_daily_streak(day(4)), 3);
+        assert_eq!(score.current_daily_streak(day(5)), 0);
+
+        // Missing a day starts again from one
+        score.record_play(day(6));
+        assert_eq!(score.daily_streak, 1);
+        assert_eq!(score.best_daily_streak, 3);
+    }
+
     // // Test theme filtering
     // #[test]
     // fn test_theme_filtering() {